@@ -0,0 +1,141 @@
+use crate::{
+    dandanplay::{Danmaku, Source, relative_size},
+    mpv::expand_path,
+};
+use anyhow::Result;
+use regex::Regex;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::LazyLock,
+};
+use tracing::info;
+
+static ENTRY: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"<d\s+p="([^"]*)"[^>]*>([^<]*)</d>"#).unwrap());
+
+/// sidecar path built from `pattern`, `{name}` is replaced with the video file name without
+/// extension, relative patterns are resolved against the directory of the video
+pub(crate) fn find_sidecar(path: &str, pattern: &str) -> Option<PathBuf> {
+    if pattern.is_empty() {
+        return None;
+    }
+
+    let video = Path::new(path);
+    let name = video.file_stem()?.to_str()?;
+    let sidecar = expand_path(&pattern.replace("{name}", name)).ok()?;
+    let sidecar = video.parent().unwrap_or(Path::new("")).join(sidecar);
+
+    sidecar.is_file().then_some(sidecar)
+}
+
+pub(crate) fn load_xml(path: &Path) -> Result<Vec<Danmaku>> {
+    let danmaku = parse_xml(&fs::read_to_string(path)?);
+    info!("Parsed {} comments from {}", danmaku.len(), path.display());
+
+    Ok(danmaku)
+}
+
+/// `<d p="time,mode,size,color,timestamp,pool,user,id">message</d>`
+pub(crate) fn parse_xml(xml: &str) -> Vec<Danmaku> {
+    ENTRY
+        .captures_iter(xml)
+        .filter_map(|captures| {
+            let mut p = captures[1].split(',');
+            let time = p
+                .next()?
+                .parse::<f64>()
+                .ok()
+                .filter(|time| time.is_finite())?;
+            // advanced (7) and code (8) comments are not plain text
+            let mode = p
+                .next()?
+                .parse::<u32>()
+                .ok()
+                .filter(|&mode| mode < 7)?
+                .into();
+            let size = p.next()?;
+            let color = p.next()?.parse().ok()?;
+            let user = p.nth(2).unwrap_or_default();
+            let mut danmaku = Danmaku::new(
+                &unescape(&captures[2]),
+                time,
+                mode,
+                color,
+                Source::Bilibili,
+                user,
+            );
+            danmaku.size = relative_size(size);
+            Some(danmaku)
+        })
+        .collect()
+}
+
+fn unescape(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        result.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let Some(end) = rest.find(';') else {
+            break;
+        };
+        let entity = &rest[1..end];
+        let c = match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            _ => entity
+                .strip_prefix("#x")
+                .map(|hex| u32::from_str_radix(hex, 16))
+                .or_else(|| entity.strip_prefix('#').map(str::parse))
+                .and_then(|code| code.ok())
+                .and_then(char::from_u32),
+        };
+        match c {
+            Some(c) => {
+                result.push(c);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                result.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    result.push_str(rest);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dandanplay::Mode;
+
+    #[test]
+    fn test_parse_xml() {
+        let xml = r#"<?xml version="1.0" encoding="UTF-8"?>
+<i>
+    <chatid>123</chatid>
+    <d p="12.5,1,25,16777215,1612345678,0,abcdef12,1">first &amp; &#x4e00;</d>
+    <d p="3.25,5,36,16711680,1612345678,0,abcdef12,2">top</d>
+    <d p="invalid">skipped</d>
+    <d p="NaN,1,25,16777215,1612345678,0,abcdef12,3">skipped</d>
+    <d p="inf,1,25,16777215,1612345678,0,abcdef12,4">skipped</d>
+</i>"#;
+
+        let danmaku = parse_xml(xml);
+        assert_eq!(danmaku.len(), 2);
+        assert_eq!(danmaku[0].message, "first & 一");
+        assert_eq!(danmaku[0].time, 12.5);
+        assert_eq!(danmaku[0].mode, Mode::Scroll);
+        assert_eq!(danmaku[0].size, 1.);
+        assert_eq!(danmaku[1].size, 1.44);
+        assert_eq!((danmaku[1].r, danmaku[1].g, danmaku[1].b), (255, 0, 0));
+        assert_eq!(danmaku[1].mode, Mode::Top);
+        assert_eq!(danmaku[1].source, Source::Bilibili);
+        assert_eq!(danmaku[1].user, "abcdef12");
+    }
+}
@@ -0,0 +1,100 @@
+use std::{collections::HashMap, sync::LazyLock};
+
+/// chinese script comments are converted to, the discriminant is dandanplay's `chConvert`
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Convert {
+    #[default]
+    No = 0,
+    Simplified = 1,
+    Traditional = 2,
+}
+
+/// common characters as simplified followed by traditional, one pair after another, characters
+/// with several counterparts such as 发 (發, 髮) or 着 (着, 著) are left out as converting them
+/// needs the surrounding phrase
+const PAIRS: &str = "\
+    与與专專业業丛叢东東丝絲丢丟两兩严嚴丧喪个個临臨为為丽麗举舉义義\
+    乌烏乐樂乔喬习習乡鄉书書买買乱亂争爭亏虧亚亞产產亩畝亲親亿億仅僅\
+    从從仓倉仪儀们們众眾优優会會伞傘伟偉传傳伤傷伦倫伪偽体體侠俠侣侶\
+    侥僥侦偵侧側侨僑侬儂俭儉债債倾傾偿償儿兒兰蘭关關兴興兹茲养養兽獸\
+    内內冈岡册冊写寫军軍农農冯馮决決况況冻凍净淨凉涼减減凑湊凤鳳凭憑\
+    凯凱击擊凿鑿刘劉则則刚剛创創删刪刹剎剂劑剑劍剥剝剧劇劝勸办辦务務\
+    动動励勵劲勁劳勞势勢勋勳匀勻区區医醫华華协協单單卖賣卢盧卫衛却卻\
+    卧臥厅廳厉厲压壓厌厭厕廁县縣参參双雙变變叙敘号號叹嘆吓嚇吕呂吗嗎\
+    吨噸听聽启啟吴吳呐吶员員呛嗆呜嗚咏詠咙嚨哑啞哗嘩响響哟喲唤喚啬嗇\
+    啸嘯喷噴嘱囑园園围圍国國图圖圆圓圣聖场場坏壞块塊坚堅坝壩坟墳坠墜\
+    垄壟垒壘执執报報墙牆壮壯声聲壳殼处處备備够夠头頭夹夾夺奪奋奮奖獎\
+    妆妝妇婦妈媽姗姍娱娛娄婁婴嬰学學宝寶实實宠寵审審宪憲宫宮宽寬宾賓\
+    对對寻尋导導寿壽将將尔爾尘塵层層届屆属屬岁歲岂豈岗崗岛島峡峽币幣\
+    师師帅帥帐帳带帶帧幀帮幫广廣庄莊庆慶库庫应應庙廟废廢开開异異弃棄\
+    张張弯彎弹彈强強归歸录錄彦彥彻徹径徑忆憶忧憂怀懷态態怜憐总總恋戀\
+    恼惱悦悅悬懸惊驚惧懼惨慘惯慣愤憤懒懶戏戲战戰户戶扑撲扩擴扫掃扬揚\
+    扰擾抚撫抛拋抢搶护護担擔拟擬拥擁拦攔拨撥择擇挤擠挥揮损損换換掷擲\
+    揽攬摄攝摇搖撑撐敌敵数數斋齋断斷无無旧舊时時旷曠昙曇显顯晋晉晒曬\
+    晓曉晕暈暂暫机機杀殺杂雜权權条條来來杨楊极極构構枪槍柜櫃标標栏欄\
+    树樹样樣档檔桥橋梦夢检檢楼樓欢歡欧歐残殘毁毀毕畢气氣汉漢汤湯沟溝\
+    没沒沪滬泪淚泽澤洁潔洒灑浅淺测測济濟浏瀏浓濃润潤涨漲渊淵温溫湾灣\
+    湿濕满滿滚滾滞滯灭滅灯燈灵靈灾災炉爐点點烂爛烦煩烧燒热熱焕煥爱愛\
+    爷爺牵牽犹猶状狀独獨狮獅猎獵猫貓献獻环環现現琐瑣电電画畫畅暢疗療\
+    疯瘋痒癢瘾癮盐鹽监監盖蓋盘盤眯瞇睁睜矫矯码碼砖磚础礎确確礼禮祸禍\
+    离離积積称稱稳穩穷窮窃竊竞競笔筆笼籠筑築简簡粮糧紧緊纠糾红紅约約\
+    级級纪紀纯純纲綱纳納纵縱纸紙线線练練组組细細织織终終经經绑綁结結\
+    绕繞绘繪给給络絡绝絕统統继繼绩績续續维維绿綠缓緩编編缘緣缩縮网網\
+    罗羅罚罰羡羨翘翹耻恥联聯聪聰肃肅肠腸肤膚肿腫胀脹胁脅胜勝脑腦脸臉\
+    舰艦艺藝节節茧繭荣榮药藥莱萊萝蘿营營蓝藍虑慮虚虛虫蟲虽雖蚁蟻蛮蠻\
+    补補衬襯袜襪装裝见見观觀规規视視览覽觉覺触觸誉譽计計订訂认認讨討\
+    让讓训訓议議讯訊记記讲講许許论論设設访訪证證评評识識诈詐诉訴词詞\
+    译譯试試诗詩诚誠话話诞誕询詢该該详詳语語误誤说說请請诸諸读讀课課\
+    谁誰调調谈談谊誼谋謀谎謊谜謎谢謝谣謠谦謙谨謹谱譜贝貝负負贡貢财財\
+    责責败敗货貨质質贩販贪貪购購贯貫贵貴费費贴貼贸貿资資赏賞赔賠赖賴\
+    赚賺赛賽赠贈赢贏赶趕趋趨跃躍践踐踪蹤车車轨軌转轉轮輪软軟轰轟轻輕\
+    载載较較辅輔辆輛辈輩输輸辑輯辞辭边邊达達迁遷过過运運还還这這进進\
+    远遠违違连連迟遲选選递遞逻邏遗遺邓鄧邮郵邻鄰郑鄭酱醬释釋鉴鑑针針\
+    钓釣钢鋼钥鑰钱錢铁鐵铃鈴银銀铺鋪链鏈销銷锁鎖锅鍋错錯键鍵镜鏡长長\
+    门門闪閃闭閉问問闯闖闲閒间間闹鬧闻聞阅閱队隊阳陽阴陰阵陣阶階际際\
+    陆陸陈陳险險随隨隐隱难難雾霧静靜韩韓页頁顶頂项項顺順顽頑顾顧顿頓\
+    预預领領颇頗频頻题題颜顏额額风風飞飛饭飯饮飲饰飾饱飽饺餃饼餅馆館\
+    马馬驱驅驶駛驾駕骂罵验驗骑騎骗騙骚騷鱼魚鲁魯鲜鮮鸟鳥鸡雞鸣鳴鸭鴨\
+    麦麥黄黃齐齊齿齒龙龍龟龜";
+
+static TO_SIMPLIFIED: LazyLock<HashMap<char, char>> =
+    LazyLock::new(|| pairs().map(|(s, t)| (t, s)).collect());
+static TO_TRADITIONAL: LazyLock<HashMap<char, char>> = LazyLock::new(|| pairs().collect());
+
+fn pairs() -> impl Iterator<Item = (char, char)> {
+    let mut chars = PAIRS.chars();
+    std::iter::from_fn(move || Some((chars.next()?, chars.next()?)))
+}
+
+/// convert `text` character by character with the offline table
+pub fn convert(text: &str, convert: Convert) -> String {
+    let table = match convert {
+        Convert::No => return text.to_string(),
+        Convert::Simplified => &*TO_SIMPLIFIED,
+        Convert::Traditional => &*TO_TRADITIONAL,
+    };
+    text.chars().map(|c| *table.get(&c).unwrap_or(&c)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_convert() {
+        assert_eq!(
+            convert("這個彈幕好難過", Convert::Simplified),
+            "这个弹幕好难过"
+        );
+        assert_eq!(
+            convert("这个弹幕好难过", Convert::Traditional),
+            "這個彈幕好難過"
+        );
+        // ambiguous characters are kept as they are
+        assert_eq!(convert("头发", Convert::Traditional), "頭发");
+        assert_eq!(convert("著名", Convert::Simplified), "著名");
+        assert_eq!(convert("草 www", Convert::Traditional), "草 www");
+        assert_eq!(convert("這個", Convert::No), "這個");
+        assert_eq!(PAIRS.chars().count() % 2, 0);
+    }
+}
@@ -1,20 +1,32 @@
-use crate::utils::{AnimeOffset, Linkage};
+use crate::utils::{AnimeOffset, FileHash, Linkage, load_cache, remove_cache, save_cache};
 use crate::{
+    bilibili,
+    chinese::{self, Convert},
     emby::{EpInfo, get_episode_info, get_series_info},
+    filename::{ReleaseName, title_season},
+    layout::text_width,
+    menu,
     mpv::osd_message,
-    options::{self, Filter},
+    options::{self, Filter, Options},
     service::DandanplayService,
 };
 use anyhow::{Ok, Result, anyhow};
+use md5::{Digest, Md5};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use std::{hint, sync::Arc};
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet, VecDeque},
+    hint,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
 use tracing::{error, info};
 use unicode_segmentation::UnicodeSegmentation;
 
 pub struct StatusInner {
-    pub x: f64,
     pub row: usize,
+    /// screen widths a scrolling comment moves per `INTERVAL` of playback
     pub step: f64,
 }
 
@@ -34,18 +46,89 @@ impl Status {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    Scroll,
+    Bottom,
+    Top,
+}
+
+impl From<u32> for Mode {
+    fn from(value: u32) -> Self {
+        match value {
+            4 => Mode::Bottom,
+            5 => Mode::Top,
+            _ => Mode::Scroll,
+        }
+    }
+}
+
 pub struct Danmaku {
     pub message: String,
     pub count: usize,
+    /// width of the message in em
+    pub width: f64,
     pub time: f64,
+    pub mode: Mode,
     pub r: u8,
     pub g: u8,
     pub b: u8,
     pub source: Source,
+    pub user: String,
     pub blocked: bool,
+    /// number of merged duplicates, 1 when nothing was merged into the comment
+    pub repeat: usize,
+    /// font size relative to the normal size of the source
+    pub size: f64,
     pub status: Status,
 }
 
+impl Danmaku {
+    pub fn new(
+        message: &str,
+        time: f64,
+        mode: Mode,
+        color: u32,
+        source: Source,
+        user: &str,
+    ) -> Self {
+        Danmaku {
+            message: message.replace('\n', "\\N"),
+            count: message.graphemes(true).count(),
+            width: text_width(message),
+            time,
+            mode,
+            r: (color / (256 * 256) % 256).try_into().unwrap(),
+            g: (color % (256 * 256) / 256).try_into().unwrap(),
+            b: (color % 256).try_into().unwrap(),
+            source,
+            user: user.to_string(),
+            blocked: false,
+            repeat: 1,
+            size: 1.,
+            status: Status::Uninitialized,
+        }
+    }
+
+    /// the message followed by the number of merged duplicates
+    pub fn text(&self) -> Cow<'_, str> {
+        if self.repeat > 1 {
+            format!("{}×{}", self.message, self.repeat).into()
+        } else {
+            self.message.as_str().into()
+        }
+    }
+
+    /// width of `text` in em
+    pub fn text_width(&self) -> f64 {
+        if self.repeat > 1 {
+            self.width + text_width(&format!("×{}", self.repeat))
+        } else {
+            self.width
+        }
+    }
+}
+
 #[derive(Deserialize)]
 struct MatchResponse {
     #[serde(rename = "isMatched")]
@@ -57,6 +140,10 @@ struct MatchResponse {
 struct Match {
     #[serde(rename = "episodeId")]
     episode_id: usize,
+    #[serde(default, rename = "animeTitle")]
+    anime_title: String,
+    #[serde(default, rename = "episodeTitle")]
+    episode_title: String,
 }
 
 #[derive(Deserialize, Serialize)]
@@ -68,57 +155,114 @@ struct Comment {
 #[derive(Deserialize, Serialize)]
 struct CommentResponse {
     comments: Vec<Comment>,
+    /// unix timestamp of the request, only present in the cache
+    #[serde(default)]
+    fetched: u64,
 }
 
 impl CommentResponse {
-    async fn get(episode_id: usize) -> Result<Self> {
-        let path = format!("/api/v2/comment/{}?withRelated=true", episode_id);
-        let request = DandanplayService::get(&path)?;
-        Ok(request.send().await?.json::<CommentResponse>().await?)
+    async fn get(path: &str) -> Result<Self> {
+        let res = DandanplayService::get(path).await?;
+        let mut comments = res.json::<CommentResponse>().await?;
+        comments.fetched = now();
+        Ok(comments)
     }
 
-    async fn save(&self, episode_id: usize) -> Result<()> {
-        use crate::mpv::expand_path;
-        use std::path::Path;
-        use tokio::io::AsyncWriteExt;
-
-        let encoded: Vec<u8> = bincode::serde::encode_to_vec(self, bincode::config::legacy())?;
-        let path_str = expand_path(&format!("~~/files/danmaku/{}", episode_id))?;
-        let path = Path::new(&path_str);
-
-        if !path.parent().expect("no parent dir").exists() {
-            std::fs::create_dir_all(path.parent().expect("no parent dir"))?;
+    /// convert the comments with the offline table, for the cache and servers which ignore
+    /// `chConvert`
+    fn convert(&mut self, to: Convert) {
+        if to != Convert::No {
+            for comment in &mut self.comments {
+                comment.m = chinese::convert(&comment.m, to);
+            }
         }
+    }
 
-        let mut file = tokio::fs::OpenOptions::new()
-            .write(true)
-            .truncate(true)
-            .create(true)
-            .open(path)
-            .await?;
-
-        file.write_all(&encoded).await?;
-
-        Ok(())
+    /// append the comments from `other` that are not already present
+    fn merge(&mut self, other: CommentResponse) {
+        let known = self
+            .comments
+            .iter()
+            .map(|c| (c.p.clone(), c.m.clone()))
+            .collect::<HashSet<_>>();
+        self.comments.extend(
+            other
+                .comments
+                .into_iter()
+                .filter(|c| !known.contains(&(c.p.clone(), c.m.clone()))),
+        );
     }
+}
 
-    async fn load(episode_id: usize) -> Result<Self> {
-        use super::mpv::expand_path;
-        use std::path::Path;
-        use tokio::fs::File;
-        use tokio::io::AsyncReadExt;
+#[derive(Clone, Deserialize, Serialize)]
+struct Related {
+    url: String,
+    /// seconds the comments are shifted by to line up with the episode
+    #[serde(default)]
+    shift: f64,
+}
 
-        let path_str = expand_path(&format!("~~/files/danmaku/{}", episode_id))?;
-        let path = Path::new(&path_str);
+#[derive(Deserialize, Serialize)]
+struct RelatedResponse {
+    relateds: Vec<Related>,
+    #[serde(default)]
+    fetched: u64,
+}
 
-        let mut file = File::open(path).await?;
-        let mut contents = vec![];
-        file.read_to_end(&mut contents).await?;
+/// a third-party page whose comments are merged with the episode
+struct ExternalSource {
+    url: String,
+    shift: f64,
+    /// added through `danmaku-add-source` instead of listed by dandanplay
+    added: bool,
+    enabled: bool,
+}
 
-        let comments: CommentResponse =
-            bincode::serde::decode_from_slice(&contents, bincode::config::legacy())?.0;
-        Ok(comments)
+/// the related pages followed by the added ones, related pages are enabled by the `related`
+/// option and added ones always, unless the user toggled them in `enabled`
+fn external_sources(
+    related: Vec<Related>,
+    added: Vec<String>,
+    enabled: &HashMap<String, bool>,
+    options: &Options,
+) -> Vec<ExternalSource> {
+    let mut sources = related
+        .into_iter()
+        .map(|related| ExternalSource {
+            enabled: options.is_related(Source::from_url(&related.url)),
+            url: related.url,
+            shift: related.shift,
+            added: false,
+        })
+        .collect::<Vec<_>>();
+    for url in added {
+        if sources.iter().all(|source| source.url != url) {
+            sources.push(ExternalSource {
+                url,
+                shift: 0.,
+                added: true,
+                enabled: true,
+            });
+        }
     }
+    for source in &mut sources {
+        if let Some(&enabled) = enabled.get(&source.url) {
+            source.enabled = enabled;
+        }
+    }
+    sources
+}
+
+/// whether a cache entry fetched at `fetched` is older than `ttl` seconds, 0 never expires
+fn is_expired(fetched: u64, ttl: u64) -> bool {
+    ttl > 0 && now().saturating_sub(fetched) >= ttl
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
 }
 
 #[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
@@ -148,18 +292,206 @@ impl From<&str> for Source {
     }
 }
 
+impl Source {
+    /// source of the comments of a third-party video page
+    fn from_url(url: &str) -> Self {
+        let host = url::Url::parse(url)
+            .ok()
+            .and_then(|url| url.host_str().map(str::to_ascii_lowercase))
+            .unwrap_or_default();
+        let is = |domain: &str| host == domain || host.ends_with(&format!(".{}", domain));
+        if is("bilibili.com") || is("b23.tv") {
+            Source::Bilibili
+        } else if is("gamer.com.tw") {
+            Source::Gamer
+        } else if is("acfun.cn") {
+            Source::AcFun
+        } else if is("qq.com") {
+            Source::QQ
+        } else if is("iqiyi.com") {
+            Source::IQIYI
+        } else {
+            Source::Unknown
+        }
+    }
+}
+
 pub async fn get_danmaku(path: &str, filter: Arc<Filter>) -> Result<Vec<Danmaku>> {
-    use crate::utils::Linkage;
-    use crate::utils::{get_localfile_hash, get_localfile_name, get_stream_hash, is_http_link};
+    load_danmaku(path, filter, false).await
+}
+
+/// like `get_danmaku`, but fetch the comments again even if the cache is still fresh
+pub async fn refresh_danmaku(path: &str, filter: Arc<Filter>) -> Result<Vec<Danmaku>> {
+    load_danmaku(path, filter, true).await
+}
+
+async fn load_danmaku(path: &str, filter: Arc<Filter>, refresh: bool) -> Result<Vec<Danmaku>> {
+    use crate::utils::is_http_link;
+
+    let sidecar = if is_http_link(path) {
+        None
+    } else {
+        bilibili::find_sidecar(path, &options::get().xml_pattern)
+    };
+
+    let mut danmaku = match sidecar {
+        Some(xml) => {
+            info!("Loading danmaku from local file: {}", xml.display());
+            let mut danmaku = bilibili::load_xml(&xml)?;
+            let convert = options::get().convert;
+            if convert != Convert::No {
+                for comment in &mut danmaku {
+                    comment.message = chinese::convert(&comment.message, convert);
+                }
+            }
+            danmaku
+        }
+        None => get_comments(get_episode_id(path).await?, refresh).await?,
+    };
+    filter_danmaku(&mut danmaku, &filter, &options::get());
+
+    Ok(danmaku)
+}
+
+/// search episodes by keyword, bind the chosen one to the current file and load its comments
+pub async fn search_danmaku(
+    path: &str,
+    keyword: &str,
+    filter: Arc<Filter>,
+) -> Result<Vec<Danmaku>> {
+    let episode_id = search_episode(keyword).await?;
+    if let Err(error) = bind_episode(path, episode_id).await {
+        error!("Failed to bind episode id {}: {}", episode_id, error);
+    }
+
+    let mut danmaku = get_comments(episode_id, false).await?;
+    filter_danmaku(&mut danmaku, &filter, &options::get());
+
+    Ok(danmaku)
+}
+
+/// filtered comments are kept but blocked, so that they can be shown again when the
+/// filter changes at runtime
+pub fn filter_danmaku(danmaku: &mut [Danmaku], filter: &Filter, options: &Options) {
+    danmaku.sort_by(|a, b| a.time.total_cmp(&b.time));
+
+    for comment in danmaku.iter_mut() {
+        comment.blocked = filter.is_blocked(comment);
+    }
+    merge_duplicates(danmaku, options);
+}
+
+/// fold comments similar to one shown less than `merge_window` seconds before into it, the
+/// duplicates are blocked and counted in `repeat` of the first one
+fn merge_duplicates(danmaku: &mut [Danmaku], options: &Options) {
+    for comment in danmaku.iter_mut() {
+        comment.repeat = 1;
+    }
+    if options.merge_window <= 0. {
+        return;
+    }
+
+    let mut heads = VecDeque::<usize>::new();
+    for index in 0..danmaku.len() {
+        if danmaku[index].blocked {
+            continue;
+        }
+        let time = danmaku[index].time;
+        while heads
+            .front()
+            .is_some_and(|&head| danmaku[head].time < time - options.merge_window)
+        {
+            heads.pop_front();
+        }
+        match heads.iter().copied().find(|&head| {
+            similar(
+                &danmaku[head].message,
+                &danmaku[index].message,
+                options.merge_similarity,
+            )
+        }) {
+            Some(head) => {
+                danmaku[head].repeat += 1;
+                danmaku[index].blocked = true;
+            }
+            None => heads.push_back(index),
+        }
+    }
+}
+
+/// whether the edit distance of two messages is within `1 - similarity` of their length
+fn similar(a: &str, b: &str, similarity: f64) -> bool {
+    if a == b {
+        return true;
+    }
+    if similarity >= 1. {
+        return false;
+    }
+
+    let a = a.chars().collect::<Vec<_>>();
+    let b = b.chars().collect::<Vec<_>>();
+    // tolerate rounding, 1 - 0.8 is slightly below 0.2
+    let limit = ((1. - similarity) * a.len().max(b.len()) as f64 + 1e-9) as usize;
+    if a.len().abs_diff(b.len()) > limit {
+        return false;
+    }
+
+    // levenshtein distance, one row at a time
+    let mut row = (0..=b.len()).collect::<Vec<_>>();
+    for (i, ca) in a.iter().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = diagonal + usize::from(ca != cb);
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+    row[b.len()] <= limit
+}
+
+async fn get_episode_id(path: &str) -> Result<usize> {
+    use crate::utils::{Linkage, LocalFiles};
+    use crate::utils::{get_localfile_name, get_stream_hash, is_http_link};
     use std::result::Result::Ok;
 
     let episode_id = if !is_http_link(path) {
         info!("Now playing non HTTP(s) files");
 
-        let hash = get_localfile_hash(path)?;
+        let mut files = LocalFiles::load().await.unwrap_or_default();
+        if let Some(episode_id) = files.get(path).and_then(|file| file.epid) {
+            info!("Success, bound episode id of local file: {}", episode_id);
+            return Ok(episode_id);
+        }
+
+        let hash = files.hash(path)?;
         let file_name = get_localfile_name(path);
 
-        get_episode_id_by_hash(&hash, &file_name).await?
+        let episode_id = match get_episode_id_by_hash(&hash, &file_name).await {
+            Ok(episode_id) => episode_id,
+            Err(error) => {
+                osd_message("trying matching with file name");
+                match get_episode_id_by_name(&file_name).await {
+                    Ok(episode_id) => {
+                        let mut linkage = Linkage::load_from_bincode().await.unwrap_or_default();
+                        linkage.insert_hashes(&hash.hash, episode_id);
+                        linkage.save_as_bincode().await?;
+                        episode_id
+                    }
+                    Err(name_error) => {
+                        error!("Failed to match by file name: {}", name_error);
+                        // the hash is still worth remembering
+                        if let Err(save_error) = files.save().await {
+                            error!("Failed to save local files: {}", save_error);
+                        }
+                        return Err(error);
+                    }
+                }
+            }
+        };
+        files.bind(path, episode_id)?;
+        files.save().await?;
+        episode_id
     } else {
         let ep_info = get_episode_info(path).await?;
 
@@ -254,68 +586,309 @@ pub async fn get_danmaku(path: &str, filter: Arc<Filter>) -> Result<Vec<Danmaku>
         }
     };
 
-    let danmaku = match CommentResponse::load(episode_id).await {
-        Ok(res) => res.comments,
-        Err(_) => {
-            let comres = CommentResponse::get(episode_id).await?;
-            comres.save(episode_id).await?;
-            comres.comments
+    Ok(episode_id)
+}
+
+/// dandanplay's own comments of the episode and those of the enabled related and added
+/// sources, each source is cached on its own
+async fn get_comments(episode_id: usize, refresh: bool) -> Result<Vec<Danmaku>> {
+    use futures::future::join_all;
+    use std::result::Result::Ok;
+    use url::form_urlencoded;
+
+    let options = options::get();
+    if let Err(error) = migrate_cache(episode_id).await {
+        error!("Failed to migrate the old cache: {}", error);
+    }
+    let mut danmaku = get_source_comments(
+        &format!("{}.dandanplay", episode_id),
+        &format!(
+            "/api/v2/comment/{}?withRelated=false&chConvert={}",
+            episode_id, options.convert as u8
+        ),
+        refresh,
+    )
+    .await?
+    .into_iter()
+    .filter_map(parse_comment)
+    .collect::<Vec<_>>();
+
+    let mut sources = get_external_sources(episode_id, refresh).await;
+    sources.retain(|source| source.enabled);
+
+    let fetched = join_all(sources.iter().map(|related| {
+        let encoded: String = form_urlencoded::byte_serialize(related.url.as_bytes()).collect();
+        let name = format!(
+            "{}.{}",
+            episode_id,
+            &hex::encode(Md5::digest(related.url.as_bytes()))[..16]
+        );
+        async move {
+            let path = format!(
+                "/api/v2/extcomment?url={}&chConvert={}",
+                encoded, options.convert as u8
+            );
+            get_source_comments(&name, &path, refresh).await
+        }
+    }))
+    .await;
+    for (related, comments) in sources.iter().zip(fetched) {
+        let comments = match comments {
+            Ok(comments) => comments,
+            Err(error) => {
+                error!("Failed to get comments from {}: {}", related.url, error);
+                continue;
+            }
+        };
+        info!("{} comments from {}", comments.len(), related.url);
+        let source = Source::from_url(&related.url);
+        danmaku.extend(comments.into_iter().filter_map(|comment| {
+            let mut comment = parse_comment(comment)?;
+            comment.time += related.shift;
+            if source != Source::Unknown {
+                comment.source = source;
+            }
+            Some(comment)
+        }));
+    }
+
+    Ok(danmaku)
+}
+
+/// older versions cached the comments along with the related ones under the episode id, keep
+/// dandanplay's own as `{id}.dandanplay` and remove the old cache only once they are saved
+async fn migrate_cache(episode_id: usize) -> Result<()> {
+    #[derive(Deserialize)]
+    struct OldCache {
+        comments: Vec<Comment>,
+    }
+
+    let old = episode_id.to_string();
+    let Some(cache) = load_cache::<OldCache>(&old).await.ok() else {
+        return Ok(());
+    };
+    let name = format!("{}.dandanplay", episode_id);
+    if load_cache::<CommentResponse>(&name).await.is_err() {
+        // the related sources are cached on their own now and tag their users
+        let comments = cache
+            .comments
+            .into_iter()
+            .filter(|comment| !comment.p.contains(",["))
+            .collect();
+        // unknown age, refreshed on the next load when `cache_ttl` is set
+        save_cache(
+            &name,
+            &CommentResponse {
+                comments,
+                fetched: 0,
+            },
+        )
+        .await?;
+        info!("Migrated the old cache of episode {}", episode_id);
+    }
+    remove_cache(&old).await
+}
+
+/// comments cached as `name`, fetched from `path` and merged with the cache when the cache
+/// expired or `refresh` is set
+async fn get_source_comments(name: &str, path: &str, refresh: bool) -> Result<Vec<Comment>> {
+    use std::result::Result::Ok;
+
+    let convert = options::get().convert;
+    let cache = load_cache::<CommentResponse>(name)
+        .await
+        .ok()
+        .map(|mut cache| {
+            cache.convert(convert);
+            cache
+        });
+    let comments = match cache {
+        Some(cache) if !refresh && !is_expired(cache.fetched, options::get().cache_ttl) => {
+            cache.comments
         }
+        cache => match CommentResponse::get(path).await {
+            Ok(mut comres) => {
+                comres.convert(convert);
+                if let Some(cache) = cache {
+                    let n = comres.comments.len();
+                    comres.merge(cache);
+                    info!(
+                        "Refreshed comments of {}, {} fetched, {} in total",
+                        name,
+                        n,
+                        comres.comments.len()
+                    );
+                }
+                save_cache(name, &comres).await?;
+                comres.comments
+            }
+            Err(error) => match cache {
+                Some(cache) => {
+                    error!("Failed to refresh comments, using cache: {}", error);
+                    cache.comments
+                }
+                None => return Err(error),
+            },
+        },
     };
 
-    let sources_rt = filter.sources_rt.lock().await;
-    let mut danmaku = danmaku
-        .into_iter()
-        .filter(|comment| filter.keywords.iter().all(|pat| !comment.m.contains(pat)))
-        .map(|comment| {
-            let mut p = comment.p.splitn(4, ',');
-            let time = p.next().unwrap().parse().unwrap();
-            _ = p.next().unwrap();
-            let color = p.next().unwrap().parse::<u32>().unwrap();
-            let user = p.next().unwrap();
-            let source = if user.chars().all(char::is_numeric) {
-                Source::Dandan
-            } else {
-                user.strip_prefix('[')
-                    .and_then(|user| user.split_once(']').map(|(source, _)| source.into()))
-                    .unwrap_or(Source::Unknown)
-            };
-            Danmaku {
-                message: comment.m.replace('\n', "\\N"),
-                count: comment.m.graphemes(true).count(),
-                time,
-                r: (color / (256 * 256)).try_into().unwrap(),
-                g: (color % (256 * 256) / 256).try_into().unwrap(),
-                b: (color % 256).try_into().unwrap(),
-                source,
-                blocked: sources_rt
-                    .as_ref()
-                    .map(|s| s.contains(&source))
-                    .unwrap_or_else(|| filter.sources.contains(&source)),
-                status: Status::Uninitialized,
+    Ok(comments)
+}
+
+/// third-party pages dandanplay relates to the episode, cached along with the comments
+async fn get_related(episode_id: usize, refresh: bool) -> Result<Vec<Related>> {
+    use std::result::Result::Ok;
+
+    let name = format!("{}.related", episode_id);
+    let cache = load_cache::<RelatedResponse>(&name).await.ok();
+    match cache {
+        Some(cache) if !refresh && !is_expired(cache.fetched, options::get().cache_ttl) => {
+            Ok(cache.relateds)
+        }
+        cache => {
+            let res = DandanplayService::get(&format!("/api/v2/related/{}", episode_id)).await;
+            match res {
+                Ok(res) => {
+                    let mut related = res.json::<RelatedResponse>().await?;
+                    related.fetched = now();
+                    save_cache(&name, &related).await?;
+                    Ok(related.relateds)
+                }
+                Err(error) => match cache {
+                    Some(cache) => {
+                        error!("Failed to refresh related sources, using cache: {}", error);
+                        Ok(cache.relateds)
+                    }
+                    None => Err(error),
+                },
             }
+        }
+    }
+}
+
+async fn get_external_sources(episode_id: usize, refresh: bool) -> Vec<ExternalSource> {
+    let related = get_related(episode_id, refresh)
+        .await
+        .unwrap_or_else(|error| {
+            error!("Failed to get related sources: {}", error);
+            Vec::new()
+        });
+    let added = load_cache(&format!("{}.added", episode_id))
+        .await
+        .unwrap_or_default();
+    let enabled = load_cache(&format!("{}.enabled", episode_id))
+        .await
+        .unwrap_or_default();
+    external_sources(related, added, &enabled, &options::get())
+}
+
+/// remember whether the comments of `url` are fetched for the episode
+async fn set_enabled(episode_id: usize, url: &str, enabled: bool) -> Result<()> {
+    let name = format!("{}.enabled", episode_id);
+    let mut urls = load_cache::<HashMap<String, bool>>(&name)
+        .await
+        .unwrap_or_default();
+    urls.insert(url.to_string(), enabled);
+    save_cache(&name, &urls).await
+}
+
+/// fetch the comments of a third-party video page for the current episode from now on
+pub async fn add_source(path: &str, url: &str, filter: Arc<Filter>) -> Result<Vec<Danmaku>> {
+    let episode_id = get_episode_id(path).await?;
+    let name = format!("{}.added", episode_id);
+    let mut urls = load_cache::<Vec<String>>(&name).await.unwrap_or_default();
+    if !urls.iter().any(|added| added == url) {
+        urls.push(url.to_string());
+        save_cache(&name, &urls).await?;
+    }
+    set_enabled(episode_id, url, true).await?;
+
+    let mut danmaku = get_comments(episode_id, false).await?;
+    filter_danmaku(&mut danmaku, &filter, &options::get());
+
+    Ok(danmaku)
+}
+
+/// let the user pick a third-party source of the current episode to enable or disable
+pub async fn toggle_source(path: &str, filter: Arc<Filter>) -> Result<Vec<Danmaku>> {
+    let episode_id = get_episode_id(path).await?;
+    let sources = get_external_sources(episode_id, false).await;
+    if sources.is_empty() {
+        return Err(anyhow!("no third-party sources"));
+    }
+    let items = sources
+        .iter()
+        .map(|source| {
+            format!(
+                "[{}] {}: {}",
+                if source.enabled { "on" } else { "off" },
+                if source.added { "added" } else { "related" },
+                source.url
+            )
         })
-        .collect::<Vec<_>>();
+        .collect();
+    let index = menu::select("Third-party sources", items).await?;
+    let source = &sources[index];
+    set_enabled(episode_id, &source.url, !source.enabled).await?;
 
-    danmaku.sort_by(|a, b| a.time.partial_cmp(&b.time).unwrap());
+    let mut danmaku = get_comments(episode_id, false).await?;
+    filter_danmaku(&mut danmaku, &filter, &options::get());
 
     Ok(danmaku)
 }
 
-async fn get_episode_id_by_hash(hash: &str, file_name: &str) -> Result<usize> {
-    let json = json!({
+/// `time,mode,color,user`, some servers also send the size as in bilibili's
+/// `time,mode,size,color,user`, None when malformed
+fn parse_comment(comment: Comment) -> Option<Danmaku> {
+    let mut p = comment.p.splitn(4, ',');
+    let (time, mode, third, rest) = (p.next()?, p.next()?, p.next()?, p.next()?);
+    // the user may contain commas, a number after the third field is the color
+    let (size, color, user) = match rest.split_once(',') {
+        Some((color, user)) if color.parse::<u32>().is_ok() => (third, color, user),
+        _ => ("", third, rest),
+    };
+    let time = time.parse::<f64>().ok().filter(|time| time.is_finite())?;
+    let mode = mode.parse::<u32>().unwrap_or(1).into();
+    let color = color.parse::<u32>().ok()?;
+    let (source, user) = if user.chars().all(char::is_numeric) {
+        (Source::Dandan, user)
+    } else {
+        user.strip_prefix('[')
+            .and_then(|user| user.split_once(']'))
+            .map(|(source, user)| (source.into(), user))
+            .unwrap_or((Source::Unknown, user))
+    };
+    let mut danmaku = Danmaku::new(&comment.m, time, mode, color, source, user);
+    danmaku.size = relative_size(size);
+    Some(danmaku)
+}
+
+/// font size in the source relative to the normal size 25, 1 when missing or invalid
+pub(crate) fn relative_size(size: &str) -> f64 {
+    size.parse::<f64>()
+        .ok()
+        .filter(|&size| size > 0.)
+        .map_or(1., |size| size / 25.)
+}
+
+async fn get_episode_id_by_hash(file_hash: &FileHash, file_name: &str) -> Result<usize> {
+    let hash = file_hash.hash.as_str();
+    let mut linkage = Linkage::load_from_bincode().await.unwrap_or_default();
+    if let Some(episode_id) = linkage.get_hashes(hash) {
+        info!("Success, bound episode id: {}", episode_id);
+        return Ok(episode_id);
+    }
+
+    let mut json = json!({
     "fileName":file_name,
     "fileHash":hash,
     "matchMode":"hashAndFileName"
     });
+    if let Some(size) = file_hash.size {
+        json["fileSize"] = size.into();
+    }
 
-    let request = DandanplayService::post("/api/v2/match")?;
-
-    let res = request
-        .header("Content-Type", "application/json")
-        .json(&json)
-        .send()
-        .await?;
+    let res = DandanplayService::post("/api/v2/match", &json).await?;
 
     if !res.status().is_success() {
         error!("Failed to matching by hash, Status: {:?}", res.status());
@@ -324,21 +897,199 @@ async fn get_episode_id_by_hash(hash: &str, file_name: &str) -> Result<usize> {
 
     let data = res.json::<MatchResponse>().await?;
 
-    if !data.is_matched {
-        error!("No matching result by hash");
-
-        Err(anyhow!("no matching episode"))
-    } else if data.matches.len() == 1 {
+    // dandanplay reports several candidates as not matched
+    if data.is_matched && data.matches.len() == 1 {
         info!(
             "Success, matching episode id: {}",
             data.matches[0].episode_id
         );
 
         Ok(data.matches[0].episode_id)
+    } else if data.matches.len() < 2 {
+        error!("No matching result by hash");
+
+        Err(anyhow!("no matching episode"))
+    } else {
+        info!("Multiple matching episodes, asking user to choose");
+
+        let items = data
+            .matches
+            .iter()
+            .map(|m| format!("{} - {} [{}]", m.anime_title, m.episode_title, m.episode_id))
+            .collect();
+        let index = menu::select("Multiple matching episodes", items).await?;
+        let episode_id = data.matches[index].episode_id;
+        info!("Success, chosen episode id: {}", episode_id);
+
+        linkage.insert_hashes(hash, episode_id);
+        linkage.save_as_bincode().await?;
+        Ok(episode_id)
+    }
+}
+
+#[derive(Deserialize)]
+struct SearchEpisodesResponse {
+    animes: Vec<SearchAnime>,
+}
+
+#[derive(Deserialize)]
+struct SearchAnime {
+    #[serde(rename = "animeTitle")]
+    anime_title: String,
+    #[serde(default, rename = "typeDescription")]
+    type_description: String,
+    episodes: Vec<SearchEpisode>,
+}
+
+#[derive(Deserialize)]
+struct SearchEpisode {
+    #[serde(rename = "episodeId")]
+    episode_id: usize,
+    #[serde(rename = "episodeTitle")]
+    episode_title: String,
+}
+
+async fn search_episode(keyword: &str) -> Result<usize> {
+    use url::form_urlencoded;
+
+    let encoded: String = form_urlencoded::byte_serialize(keyword.as_bytes()).collect();
+    let path = format!("/api/v2/search/episodes?anime={}", encoded);
+
+    let res = DandanplayService::get(&path).await?;
+
+    if !res.status().is_success() {
+        error!("Failed to search episodes, Status: {:?}", res.status());
+        return Err(anyhow!("failed to search episodes, try again later"));
+    }
+
+    let mut animes = res.json::<SearchEpisodesResponse>().await?.animes;
+    animes.retain(|anime| !anime.episodes.is_empty());
+
+    if animes.is_empty() {
+        error!("No search result for {}", keyword);
+        return Err(anyhow!("no episode found for {}", keyword));
+    }
+
+    let anime = if animes.len() == 1 {
+        animes.swap_remove(0)
+    } else {
+        let items = animes
+            .iter()
+            .map(|a| format!("{} ({})", a.anime_title, a.type_description))
+            .collect();
+        let index = menu::select(&format!("Search results for {}", keyword), items).await?;
+        animes.swap_remove(index)
+    };
+
+    let items = anime
+        .episodes
+        .iter()
+        .map(|e| e.episode_title.clone())
+        .collect();
+    let index = menu::select(&anime.anime_title, items).await?;
+    let episode_id = anime.episodes[index].episode_id;
+    info!("Success, searched episode id: {}", episode_id);
+
+    Ok(episode_id)
+}
+
+/// how an anime was picked from the search results for a release name
+#[derive(Debug)]
+enum Confidence {
+    /// the only result
+    High,
+    /// the only result of the parsed season
+    Medium,
+    /// picked by the user
+    Chosen,
+}
+
+/// search the title parsed from the release name and pick the episode with the parsed number
+async fn get_episode_id_by_name(file_name: &str) -> Result<usize> {
+    use url::form_urlencoded;
+
+    let release = ReleaseName::parse(file_name);
+    info!("Parsed file name: {:?}", release);
+    let Some(episode) = release.episode.filter(|_| !release.title.is_empty()) else {
+        return Err(anyhow!("no title and episode in {}", file_name));
+    };
+
+    let encoded: String = form_urlencoded::byte_serialize(release.title.as_bytes()).collect();
+    let path = format!(
+        "/api/v2/search/episodes?anime={}&episode={}",
+        encoded, episode
+    );
+    let res = DandanplayService::get(&path).await?;
+
+    if !res.status().is_success() {
+        error!("Failed to search episodes, Status: {:?}", res.status());
+        return Err(anyhow!("failed to search episodes"));
+    }
+
+    let mut animes = res.json::<SearchEpisodesResponse>().await?.animes;
+    animes.retain(|anime| !anime.episodes.is_empty());
+
+    // the season in the release name picks between the seasons of a series
+    let season = release.season.unwrap_or(1);
+    let seasons = animes
+        .iter()
+        .enumerate()
+        .filter(|(_, anime)| title_season(&anime.anime_title) == season)
+        .map(|(index, _)| index)
+        .collect::<Vec<_>>();
+    let (anime, confidence) = match (animes.len(), &seasons[..]) {
+        (0, _) => return Err(anyhow!("no episode found for {}", release.title)),
+        (1, &[_]) => (0, Confidence::High),
+        (_, &[index]) => (index, Confidence::Medium),
+        _ => {
+            let items = animes
+                .iter()
+                .map(|a| format!("{} ({})", a.anime_title, a.type_description))
+                .collect();
+            let title = format!("Search results for {}", release.title);
+            (menu::select(&title, items).await?, Confidence::Chosen)
+        }
+    };
+    let anime = &animes[anime];
+    let episode_id = anime.episodes[0].episode_id;
+    info!(
+        "Success, matching episode id by file name: {} ({} - {}), confidence: {:?}",
+        episode_id, anime.anime_title, anime.episodes[0].episode_title, confidence
+    );
+    Ok(episode_id)
+}
+
+/// remember `episode_id` for the file at `path`, by hash for local files and plain streams,
+/// by host and item id for Emby
+async fn bind_episode(path: &str, episode_id: usize) -> Result<()> {
+    use crate::utils::{LocalFiles, get_stream_hash, is_http_link};
+
+    let mut linkage = Linkage::load_from_bincode().await.unwrap_or_default();
+
+    if !is_http_link(path) {
+        let mut files = LocalFiles::load().await.unwrap_or_default();
+        let hash = files.hash(path)?;
+        files.bind(path, episode_id)?;
+        files.save().await?;
+        linkage.insert_hashes(&hash.hash, episode_id);
     } else {
-        error!("Too many results");
-        Err(anyhow!("multiple matching episodes"))
+        let ep_info = get_episode_info(path).await?;
+        if ep_info.status {
+            if ep_info.r#type == "tvseries" {
+                let anime_id = episode_id / 10000;
+                let offset =
+                    (episode_id - (anime_id * 10000)) as i64 - ep_info.item_info.ep_index as i64;
+
+                let ani_offset = AnimeOffset { anime_id, offset };
+                linkage.insert_seasons(&ep_info.host, &ep_info.item_info.se_id, ani_offset);
+            }
+            linkage.insert_items(&ep_info.host, &ep_info.item_info.item_id, episode_id);
+        } else {
+            linkage.insert_hashes(&get_stream_hash(path).await?.hash, episode_id);
+        }
     }
+
+    linkage.save_as_bincode().await
 }
 
 // total shit
@@ -367,12 +1118,7 @@ async fn get_episode_id_by_info(ep_info: &EpInfo, linkage: &mut Linkage) -> Resu
         encoded_name, ep_type
     );
 
-    let request = DandanplayService::get(&path)?;
-
-    let res = request
-        .header("Content-Type", "application/json")
-        .send()
-        .await?;
+    let res = DandanplayService::get(&path).await?;
 
     if !res.status().is_success() {
         error!(
@@ -389,7 +1135,7 @@ async fn get_episode_id_by_info(ep_info: &EpInfo, linkage: &mut Linkage) -> Resu
         return Err(anyhow!("no matching episode with info"));
     }
 
-    if ["true", "on", "enable"].contains(&options::OPTIONS.log.to_ascii_lowercase().as_str()) {
+    if ["true", "on", "enable"].contains(&options::get().log.to_ascii_lowercase().as_str()) {
         let dandan_search = data
             .animes
             .iter()
@@ -673,9 +1419,7 @@ pub async fn _get_episode_num_dan(epid: usize) -> Result<u64> {
     let anime_id = epid / 10000;
     let path = format!("/api/v2/bangumi/{}", anime_id);
 
-    let request = DandanplayService::get(&path)?;
-
-    let res = request.send().await?;
+    let res = DandanplayService::get(&path).await?;
 
     if !res.status().is_success() {
         error!(
@@ -699,3 +1443,351 @@ pub async fn _get_episode_num_dan(epid: usize) -> Result<u64> {
     });
     Ok(sum)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::host::{
+        fake::{FakeHost, LOCK},
+        set_host,
+    };
+    use std::{
+        future::Future,
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+        runtime::Builder,
+    };
+
+    fn comment(p: &str, m: &str) -> Comment {
+        Comment {
+            p: p.to_string(),
+            m: m.to_string(),
+        }
+    }
+
+    /// answer requests with the body of the first route the path starts with, returns the
+    /// base url and the number of requests answered
+    async fn serve(routes: Vec<(&'static str, &'static str)>) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let count = Arc::new(AtomicUsize::new(0));
+        let served = count.clone();
+        tokio::spawn(async move {
+            while let Result::Ok((mut stream, _)) = listener.accept().await {
+                // the headers, then as many bytes as the content length says
+                let mut request = Vec::new();
+                let mut buffer = [0; 4096];
+                loop {
+                    let n = stream.read(&mut buffer).await.unwrap_or(0);
+                    request.extend_from_slice(&buffer[..n]);
+                    let text = String::from_utf8_lossy(&request);
+                    let complete = text.find("\r\n\r\n").is_some_and(|end| {
+                        let length = text[..end]
+                            .lines()
+                            .find_map(|line| {
+                                let line = line.to_ascii_lowercase();
+                                line.strip_prefix("content-length:")?.trim().parse().ok()
+                            })
+                            .unwrap_or(0);
+                        request.len() >= end + 4 + length
+                    });
+                    if n == 0 || complete {
+                        break;
+                    }
+                }
+                let text = String::from_utf8_lossy(&request);
+                let path = text.split_whitespace().nth(1).unwrap_or_default();
+                let response = match routes.iter().find(|(route, _)| path.starts_with(route)) {
+                    Some((_, body)) => format!(
+                        "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        body.len(),
+                        body
+                    ),
+                    None => {
+                        "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                            .to_string()
+                    }
+                };
+                served.fetch_add(1, Ordering::SeqCst);
+                _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+        (url, count)
+    }
+
+    /// run `test` against a fake dandanplay answering `routes`, with no episode bound yet
+    fn with_api<F: Future>(
+        routes: Vec<(&'static str, &'static str)>,
+        test: impl FnOnce(Arc<FakeHost>, Arc<AtomicUsize>) -> F,
+    ) -> F::Output {
+        let _lock = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let fake = Arc::new(FakeHost::default());
+        set_host(fake.clone());
+        Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(async {
+                let (url, count) = serve(routes).await;
+                options::set(Options {
+                    api_url: url,
+                    api_auth: false,
+                    ..Default::default()
+                });
+                for name in ["database", "hashes", "files"] {
+                    remove_cache(name).await.unwrap();
+                }
+                let output = test(fake, count).await;
+                options::set(Options::default());
+                output
+            })
+    }
+
+    /// send `actions` to the menu once it is shown
+    async fn choose(fake: &FakeHost, actions: &[&str]) {
+        while fake.frames(1).is_empty() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        for action in actions {
+            menu::handle(action).await.unwrap();
+        }
+    }
+
+    const MATCHES: &str = r#"{"isMatched":false,"matches":[
+        {"episodeId":10010001,"animeTitle":"A","episodeTitle":"1"},
+        {"episodeId":10020001,"animeTitle":"B","episodeTitle":"1"}]}"#;
+
+    #[test]
+    fn test_match_menu() {
+        with_api(vec![("/api/v2/match", MATCHES)], |fake, count| async move {
+            let hash = FileHash {
+                hash: "0123456789abcdef".to_string(),
+                size: Some(1),
+            };
+            let (episode_id, ()) = futures::join!(
+                get_episode_id_by_hash(&hash, "a.mkv"),
+                choose(&fake, &["down", "select"])
+            );
+            assert_eq!(episode_id.unwrap(), 10020001);
+            // remembered without asking again
+            assert_eq!(
+                get_episode_id_by_hash(&hash, "a.mkv").await.unwrap(),
+                10020001
+            );
+            assert_eq!(count.load(Ordering::SeqCst), 1);
+        });
+    }
+
+    #[test]
+    fn test_match_menu_cancelled() {
+        with_api(vec![("/api/v2/match", MATCHES)], |fake, _| async move {
+            let hash = FileHash {
+                hash: "fedcba9876543210".to_string(),
+                size: None,
+            };
+            let (episode_id, ()) = futures::join!(
+                get_episode_id_by_hash(&hash, "a.mkv"),
+                choose(&fake, &["cancel"])
+            );
+            assert!(episode_id.is_err());
+            let linkage = Linkage::load_from_bincode().await.unwrap();
+            assert_eq!(linkage.get_hashes(&hash.hash), None);
+        });
+    }
+
+    #[test]
+    fn test_migrate_cache() {
+        #[derive(Serialize)]
+        struct OldCache {
+            comments: Vec<Comment>,
+        }
+
+        with_api(Vec::new(), |_, _| async {
+            remove_cache("10040001.dandanplay").await.unwrap();
+            let old = OldCache {
+                comments: vec![
+                    comment("1.00,1,16777215,123", "own"),
+                    comment("2.00,1,16777215,[BiliBili]a", "related"),
+                ],
+            };
+            save_cache("10040001", &old).await.unwrap();
+
+            migrate_cache(10040001).await.unwrap();
+            let cache = load_cache::<CommentResponse>("10040001.dandanplay")
+                .await
+                .unwrap();
+            assert_eq!(cache.comments.len(), 1);
+            assert_eq!(cache.comments[0].m, "own");
+            assert_eq!(cache.fetched, 0);
+            assert!(load_cache::<Vec<Comment>>("10040001").await.is_err());
+        });
+    }
+
+    #[test]
+    fn test_search_binds_episode() {
+        let routes = vec![
+            (
+                "/api/v2/search/episodes",
+                r#"{"animes":[{"animeTitle":"C","typeDescription":"TV","episodes":[
+                    {"episodeId":10030001,"episodeTitle":"1"},
+                    {"episodeId":10030002,"episodeTitle":"2"}]}]}"#,
+            ),
+            (
+                "/api/v2/comment/10030002",
+                r#"{"comments":[{"p":"1.00,1,16777215,[BiliBili]a","m":"hi"}]}"#,
+            ),
+            ("/api/v2/related/10030002", r#"{"relateds":[]}"#),
+        ];
+        with_api(routes, |fake, count| async move {
+            for name in ["10030002.dandanplay", "10030002.related"] {
+                remove_cache(name).await.unwrap();
+            }
+            let path =
+                std::env::temp_dir().join(format!("mpv-danmaku-{}-search.mkv", std::process::id()));
+            std::fs::write(&path, b"abc").unwrap();
+            let path = path.to_str().unwrap();
+
+            let (danmaku, ()) = futures::join!(
+                search_danmaku(path, "C", Arc::default()),
+                choose(&fake, &["down", "select"])
+            );
+            assert_eq!(danmaku.unwrap().len(), 1);
+
+            // the next load neither matches nor searches again
+            let searched = count.load(Ordering::SeqCst);
+            let danmaku = get_danmaku(path, Arc::default()).await.unwrap();
+            assert_eq!(danmaku[0].message, "hi");
+            assert_eq!(count.load(Ordering::SeqCst), searched);
+            std::fs::remove_file(path).unwrap();
+        });
+    }
+
+    #[test]
+    fn test_merge_comments() {
+        let mut fresh = CommentResponse {
+            comments: vec![
+                comment("1.00,1,16777215,1", "a"),
+                comment("2.00,1,16777215,2", "b"),
+            ],
+            fetched: 100,
+        };
+        let cache = CommentResponse {
+            comments: vec![
+                comment("1.00,1,16777215,1", "a"),
+                comment("0.50,1,16777215,3", "c"),
+            ],
+            fetched: 0,
+        };
+        fresh.merge(cache);
+
+        assert_eq!(fresh.comments.len(), 3);
+        assert_eq!(fresh.comments[2].m, "c");
+        assert!(!is_expired(fresh.fetched, 0));
+        assert!(is_expired(fresh.fetched, 1));
+    }
+
+    #[test]
+    fn test_parse_comment() {
+        let parsed = parse_comment(comment("1.50,5,16711680,[BiliBili]a,b", "hi")).unwrap();
+        assert_eq!(parsed.time, 1.5);
+        assert!(parsed.mode == Mode::Top);
+        assert_eq!((parsed.r, parsed.g, parsed.b), (255, 0, 0));
+        assert!(parsed.source == Source::Bilibili);
+        assert_eq!(parsed.user, "a,b");
+        assert_eq!(parsed.size, 1.);
+
+        let sized = parse_comment(comment("1.50,1,36,16777215,[BiliBili]a,b", "hi")).unwrap();
+        assert_eq!(sized.size, 36. / 25.);
+        assert_eq!(sized.user, "a,b");
+
+        assert!(parse_comment(comment("1.50,1,16777215", "hi")).is_none());
+        assert!(parse_comment(comment("x,1,16777215,1", "hi")).is_none());
+        assert!(parse_comment(comment("1.50,1,white,1", "hi")).is_none());
+    }
+
+    #[test]
+    fn test_related_source() {
+        assert_eq!(
+            Source::from_url("https://www.bilibili.com/bangumi/play/ep1"),
+            Source::Bilibili
+        );
+        assert_eq!(
+            Source::from_url("https://ani.gamer.com.tw/animeVideo.php?sn=1"),
+            Source::Gamer
+        );
+        assert_eq!(
+            Source::from_url("https://notbilibili.com/1"),
+            Source::Unknown
+        );
+
+        let options = Options {
+            related: "bilibili,acfun".into(),
+            ..Default::default()
+        };
+        assert!(options.is_related(Source::Bilibili));
+        assert!(!options.is_related(Source::Gamer));
+        assert!(Options::default().is_related(Source::Gamer));
+
+        let related = |url: &str| Related {
+            url: url.to_string(),
+            shift: 1.,
+        };
+        let bilibili = "https://www.bilibili.com/bangumi/play/ep1";
+        let gamer = "https://ani.gamer.com.tw/animeVideo.php?sn=1";
+        let acfun = "https://www.acfun.cn/bangumi/aa1";
+        let added = "https://www.bilibili.com/video/BV1";
+        let sources = external_sources(
+            vec![related(bilibili), related(gamer), related(acfun)],
+            vec![added.to_string(), gamer.to_string()],
+            &HashMap::from([(acfun.to_string(), false), (added.to_string(), false)]),
+            &options,
+        );
+        assert_eq!(
+            sources
+                .iter()
+                .map(|source| (source.url.as_str(), source.added, source.enabled))
+                .collect::<Vec<_>>(),
+            [
+                (bilibili, false, true),
+                (gamer, false, false),
+                (acfun, false, false),
+                (added, true, false),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_merge_duplicates() {
+        let danmaku =
+            |message, time| Danmaku::new(message, time, Mode::Scroll, 0, Source::Dandan, "1");
+        let mut comments = vec![
+            danmaku("2333", 0.),
+            danmaku("hello", 1.),
+            danmaku("2333", 2.),
+            danmaku("23333", 3.),
+            danmaku("2333", 10.),
+        ];
+        let mut options = Options {
+            merge_window: 5.,
+            ..Default::default()
+        };
+        filter_danmaku(&mut comments, &Filter::default(), &options);
+        let shown = |comments: &[Danmaku]| {
+            comments
+                .iter()
+                .filter(|c| !c.blocked)
+                .map(|c| c.text().into_owned())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(shown(&comments), ["2333×2", "hello", "23333", "2333"]);
+
+        options.merge_similarity = 0.8;
+        filter_danmaku(&mut comments, &Filter::default(), &options);
+        assert_eq!(shown(&comments), ["2333×3", "hello", "2333"]);
+        assert!((comments[0].text_width() - text_width("2333×3")).abs() < 1e-9);
+    }
+}
@@ -1,14 +1,46 @@
-use super::utils::CLIENT;
+use super::utils::client;
 use anyhow::{Context, Ok, Result, anyhow};
 use regex::Regex;
+use reqwest::RequestBuilder;
 use serde::Deserialize;
 use std::fmt::Display;
 use tracing::{error, info};
 use url::Url;
 
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Server {
+    #[default]
+    Emby,
+    Jellyfin,
+}
+
+impl Server {
+    /// attach the access token in the form the server expects
+    pub fn auth(&self, request: RequestBuilder, api_key: &str) -> RequestBuilder {
+        match self {
+            Server::Emby => request.header("X-Emby-Token", api_key),
+            Server::Jellyfin => request.header(
+                "Authorization",
+                format!("MediaBrowser Token=\"{}\"", api_key),
+            ),
+        }
+    }
+}
+
+impl Display for Server {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Server::Emby => write!(f, "Emby"),
+            Server::Jellyfin => write!(f, "Jellyfin"),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub(crate) struct P3 {
     pub host: String,
+    pub base_url: String,
+    pub server: Server,
     pub item_id: String,
     pub api_key: String,
 }
@@ -27,33 +59,79 @@ pub(crate) fn extract_params(video_url: &str) -> Result<P3> {
         url.host_str().expect("host not found")
     );
 
-    // api_key
+    // api_key, Jellyfin also accepts ApiKey
     let Some(api_key) = url
         .query_pairs()
-        .find(|(key, _)| key == "api_key")
+        .find(|(key, _)| key.eq_ignore_ascii_case("api_key") || key.eq_ignore_ascii_case("apikey"))
         .map(|(_, value)| value)
     else {
         error!("api_key not founf");
         return Err(anyhow!("api_key not found"));
     };
 
-    let pattern = Regex::new(r"^.*/videos/(\d+)/.*")?;
+    // Emby item ids are numeric, Jellyfin ones are GUIDs
+    let pattern = Regex::new(
+        r"(?i)^(.*)/videos/(\d+|[0-9a-f]{32}|[0-9a-f]{8}-[0-9a-f]{4}-[0-9a-f]{4}-[0-9a-f]{4}-[0-9a-f]{12})/.*",
+    )?;
 
     // item_id
-    let item_id = if let Some(captures) = pattern.captures(url.path()) {
-        String::from(&captures[1])
+    let (prefix, item_id) = if let Some(captures) = pattern.captures(url.path()) {
+        (captures[1].to_string(), captures[2].to_string())
     } else {
         error!("ItemId not found");
         return Err(anyhow!("item_id not found"));
     };
 
+    let origin = url.origin().ascii_serialization();
+    let (server, base_url) = if item_id.chars().all(|c| c.is_ascii_digit()) {
+        // videos are served both with and without `/emby`, the api only with it, behind
+        // whatever prefix a reverse proxy adds
+        let prefix = prefix.trim_end_matches('/');
+        let api = if prefix.to_ascii_lowercase().ends_with("/emby") {
+            ""
+        } else {
+            "/emby"
+        };
+        (Server::Emby, format!("{}{}{}", origin, prefix, api))
+    } else {
+        (Server::Jellyfin, format!("{}{}", origin, prefix))
+    };
+
     Ok(P3 {
         host,
+        base_url,
+        server,
         item_id,
         api_key: api_key.to_string(),
     })
 }
 
+/// request for the original file of an Emby or Jellyfin video, instead of a possibly
+/// transcoded stream
+pub(crate) fn original_file(video_url: &str) -> Option<RequestBuilder> {
+    let P3 {
+        base_url,
+        server,
+        item_id,
+        api_key,
+        ..
+    } = extract_params(video_url).ok()?;
+    let mut url = Url::parse(&format!(
+        "{}/Videos/{}/stream?static=true",
+        base_url, item_id
+    ))
+    .ok()?;
+    // the version being played when the item has several
+    if let Some((_, id)) = Url::parse(video_url)
+        .ok()?
+        .query_pairs()
+        .find(|(key, _)| key.eq_ignore_ascii_case("MediaSourceId"))
+    {
+        url.query_pairs_mut().append_pair("MediaSourceId", &id);
+    }
+    Some(server.auth(client().get(url), &api_key))
+}
+
 #[derive(Debug)]
 pub(crate) struct ItemInfo {
     pub name: String,
@@ -83,6 +161,8 @@ impl Default for ItemInfo {
 pub(crate) struct EpInfo {
     pub r#type: String,
     pub host: String,
+    pub base_url: String,
+    pub server: Server,
     pub api_key: String,
     pub item_info: ItemInfo,
     pub status: bool,
@@ -93,6 +173,8 @@ impl Default for EpInfo {
         Self {
             r#type: "unknown".to_string(),
             host: "unknown".to_string(),
+            base_url: "unknown".to_string(),
+            server: Server::default(),
             api_key: "unknown".to_string(),
             item_info: ItemInfo::default(),
             status: false,
@@ -103,7 +185,8 @@ impl Default for EpInfo {
 impl Display for EpInfo {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let str = format!(
-            "[Type: {}  Name: {}  Series Name: {}  Season Number: {}  Episode Number: {}  SeriesId: {}  SeasonId: {}  Status: {}]",
+            "[Server: {}  Type: {}  Name: {}  Series Name: {}  Season Number: {}  Episode Number: {}  SeriesId: {}  SeasonId: {}  Status: {}]",
+            self.server,
             self.r#type,
             self.item_info.name,
             self.item_info.ss_name,
@@ -179,6 +262,8 @@ pub(crate) async fn get_episode_info(video_url: &str) -> Result<EpInfo> {
 
     let P3 {
         host,
+        base_url,
+        server,
         item_id,
         api_key,
     } = match extract_params(video_url) {
@@ -186,17 +271,14 @@ pub(crate) async fn get_episode_info(video_url: &str) -> Result<EpInfo> {
         Err(_) => return Ok(EpInfo::default()),
     };
 
-    let url = format!("{}/emby/Items?Ids={}&reqformat=json", host, item_id);
+    let url = format!("{}/Items?Ids={}&reqformat=json", base_url, item_id);
 
-    let response = CLIENT
-        .get(url)
-        .header("X-Emby-Token", &api_key)
-        .send()
-        .await?;
+    let response = server.auth(client().get(url), &api_key).send().await?;
 
     if !response.status().is_success() {
         error!(
-            "Failed to fetch ep info from Emby server, Status: {:?}",
+            "Failed to fetch ep info from {} server, Status: {:?}",
+            server,
             response.status()
         );
 
@@ -216,6 +298,8 @@ pub(crate) async fn get_episode_info(video_url: &str) -> Result<EpInfo> {
             Ok(EpInfo {
                 r#type: "ova".to_string(),
                 host,
+                base_url,
+                server,
                 api_key,
                 item_info: ItemInfo {
                     name: epdata.items[0].name.clone(),
@@ -232,6 +316,8 @@ pub(crate) async fn get_episode_info(video_url: &str) -> Result<EpInfo> {
             Ok(EpInfo {
                 r#type: "tvseries".to_string(),
                 host,
+                base_url,
+                server,
                 api_key,
                 item_info: ItemInfo {
                     name: epdata.items[0].name.clone(),
@@ -249,6 +335,8 @@ pub(crate) async fn get_episode_info(video_url: &str) -> Result<EpInfo> {
         Ok(EpInfo {
             r#type: "movie".to_string(),
             host,
+            base_url,
+            server,
             api_key,
             item_info: ItemInfo {
                 name: epdata.items[0].name.clone(),
@@ -296,21 +384,22 @@ struct Episode {
 pub(crate) async fn get_series_info(ep_info: &EpInfo) -> Result<Vec<(u64, u64)>> {
     use std::result::Result::Ok;
 
-    let host = ep_info.host.clone();
+    let base_url = ep_info.base_url.clone();
+    let server = ep_info.server;
     let api_key = ep_info.api_key.clone();
     let series_id = ep_info.item_info.ss_id.clone();
 
-    let seasons_url = format!("{}/emby/Shows/{}/Seasons?reqformat=json", host, series_id);
+    let seasons_url = format!("{}/Shows/{}/Seasons?reqformat=json", base_url, series_id);
 
-    let res = CLIENT
-        .get(seasons_url)
-        .header("X-Emby-Token", &api_key)
+    let res = server
+        .auth(client().get(seasons_url), &api_key)
         .send()
         .await?;
 
     if !res.status().is_success() {
         error!(
-            "Failed to fetch seasons info from Emby server, Status: {:?}",
+            "Failed to fetch seasons info from {} server, Status: {:?}",
+            server,
             res.status()
         );
 
@@ -333,18 +422,18 @@ pub(crate) async fn get_series_info(ep_info: &EpInfo) -> Result<Vec<(u64, u64)>>
             let sid = season.season_id;
 
             let episodes_url = format!(
-                "{}/emby/Shows/{}/Episodes?SeasonId={}&reqformat=json",
-                host, series_id, sid
+                "{}/Shows/{}/Episodes?SeasonId={}&reqformat=json",
+                base_url, series_id, sid
             );
-            let res = CLIENT
-                .get(episodes_url)
-                .header("X-Emby-Token", &api_key)
+            let res = server
+                .auth(client().get(episodes_url), &api_key)
                 .send()
                 .await?;
 
             if !res.status().is_success() {
                 error!(
-                    "Failed to fetch episodes info from Emby server, Status: {}",
+                    "Failed to fetch episodes info from {} server, Status: {}",
+                    server,
                     res.status()
                 );
 
@@ -370,7 +459,7 @@ pub(crate) async fn get_series_info(ep_info: &EpInfo) -> Result<Vec<(u64, u64)>>
             episodes_list.push((season.season_num, sum));
         }
     }
-    info!("Episodes list from Emby: {:?}", episodes_list);
+    info!("Episodes list from {}: {:?}", server, episodes_list);
 
     Ok(episodes_list)
 }
@@ -378,23 +467,21 @@ pub(crate) async fn get_series_info(ep_info: &EpInfo) -> Result<Vec<(u64, u64)>>
 pub(crate) async fn _get_episode_num_emby(ep_info: &EpInfo) -> Result<u64> {
     let series_id = ep_info.item_info.ss_id.clone();
     let season_id = ep_info.item_info.se_id.clone();
-    let host = ep_info.host.clone();
+    let base_url = ep_info.base_url.clone();
+    let server = ep_info.server;
     let api_key = ep_info.api_key.clone();
 
     let url = format!(
-        "{}/emby/Shows/{}/Episodes?SeasonId={}&reqformat=json",
-        host, series_id, season_id
+        "{}/Shows/{}/Episodes?SeasonId={}&reqformat=json",
+        base_url, series_id, season_id
     );
 
-    let res = CLIENT
-        .get(url)
-        .header("X-Emby-Token", &api_key)
-        .send()
-        .await?;
+    let res = server.auth(client().get(url), &api_key).send().await?;
 
     if !res.status().is_success() {
         error!(
-            "Failed to fetch seasons info from Emby server, Status: {:?}",
+            "Failed to fetch seasons info from {} server, Status: {:?}",
+            server,
             res.status()
         );
 
@@ -419,3 +506,57 @@ pub(crate) async fn _get_episode_num_emby(ep_info: &EpInfo) -> Result<u64> {
 
     Ok(sum)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_extract_params() {
+        let emby =
+            extract_params("https://emby.example.com/emby/videos/12345/original.mkv?api_key=abc")
+                .unwrap();
+        assert_eq!(emby.server, Server::Emby);
+        assert_eq!(emby.host, "https://emby.example.com");
+        assert_eq!(emby.base_url, "https://emby.example.com/emby");
+        assert_eq!(emby.item_id, "12345");
+        assert_eq!(emby.api_key, "abc");
+
+        let proxied = extract_params(
+            "https://example.com/media/videos/12345/stream.mkv?static=true&api_key=abc",
+        )
+        .unwrap();
+        assert_eq!(proxied.base_url, "https://example.com/media/emby");
+        let proxied =
+            extract_params("https://example.com/media/emby/videos/12345/stream?api_key=abc")
+                .unwrap();
+        assert_eq!(proxied.base_url, "https://example.com/media/emby");
+
+        let jellyfin = extract_params(
+            "http://media.lan:8096/jellyfin/Videos/0123456789abcdef0123456789abcdef/stream?static=true&ApiKey=def",
+        )
+        .unwrap();
+        assert_eq!(jellyfin.server, Server::Jellyfin);
+        assert_eq!(jellyfin.host, "http://media.lan");
+        assert_eq!(jellyfin.base_url, "http://media.lan:8096/jellyfin");
+        assert_eq!(jellyfin.item_id, "0123456789abcdef0123456789abcdef");
+        assert_eq!(jellyfin.api_key, "def");
+
+        assert!(extract_params("https://example.com/videos/12345/stream").is_err());
+    }
+
+    #[test]
+    fn test_original_file() {
+        let request = original_file(
+            "https://emby.example.com/emby/videos/12345/original.mkv?MediaSourceId=mediasource_67&api_key=abc",
+        )
+        .unwrap()
+        .build()
+        .unwrap();
+        assert_eq!(
+            request.url().as_str(),
+            "https://emby.example.com/emby/Videos/12345/stream?static=true&MediaSourceId=mediasource_67"
+        );
+        assert_eq!(request.headers()["X-Emby-Token"], "abc");
+    }
+}
@@ -0,0 +1,225 @@
+use crate::{
+    dandanplay::{Danmaku, Mode},
+    layout::{FIXED_DURATION, INTERVAL, Layout, MIN_STEP, Row, bgr, style},
+    mpv::expand_path,
+    options::Options,
+    utils::is_http_link,
+};
+use anyhow::Result;
+use rand::rng;
+use std::{fmt::Write, fs, path::Path};
+
+/// the scrolling comment which leaves a row last
+#[derive(Clone, Copy)]
+struct Tail {
+    time: f64,
+    step: f64,
+    width: f64,
+    end: f64,
+}
+
+/// `<video>.danmaku.ass` next to local files, `~~/files/danmaku/export.ass` for streams
+pub fn default_path(video: &str) -> Result<String> {
+    if is_http_link(video) {
+        expand_path("~~/files/danmaku/export.ass")
+    } else {
+        Ok(Path::new(video)
+            .with_extension("danmaku.ass")
+            .to_string_lossy()
+            .into_owned())
+    }
+}
+
+pub fn save(
+    path: &str,
+    comments: &[Danmaku],
+    delay: f64,
+    osd_width: f64,
+    osd_height: f64,
+    options: &Options,
+) -> Result<()> {
+    let path = Path::new(path);
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(
+        path,
+        export_ass(comments, delay, osd_width, osd_height, options),
+    )?;
+    Ok(())
+}
+
+/// lay out the whole timeline with the lane allocation used by the renderer and
+/// produce a complete ASS subtitle
+pub fn export_ass(
+    comments: &[Danmaku],
+    delay: f64,
+    osd_width: f64,
+    osd_height: f64,
+    options: &Options,
+) -> String {
+    let mut layout = Layout::new(osd_width, osd_height, options);
+    let width = layout.width;
+    // pixels per second of a comment moving `step` screen widths per tick
+    let velocity = |step: f64| width * step * options.speed / INTERVAL;
+    let mut tails: Vec<Option<Tail>> = vec![None; layout.rows.len()];
+    let mut rng = rng();
+
+    let mut ass = header(&layout, options);
+    for comment in comments.iter().filter(|c| !c.blocked) {
+        let time = comment.time + delay;
+        if time < 0. {
+            continue;
+        }
+
+        let span = layout.span(comment, options);
+        if comment.mode != Mode::Scroll {
+            let Some(row) = layout.fixed_row(comment.mode, time, span, !options.no_overlap) else {
+                continue;
+            };
+            let end = time + FIXED_DURATION;
+            layout.occupy_fixed_row(comment.mode, row, span, end);
+            dialogue(
+                &mut ass,
+                time,
+                end,
+                &format!(
+                    "\\an8\\pos({},{}){}",
+                    width / 2.,
+                    layout.y(comment.mode, row, span),
+                    style(comment, options)
+                ),
+                &comment.text(),
+            );
+            continue;
+        }
+
+        // lanes as the renderer sees them when the comment enters the screen
+        for (row, tail) in layout.rows.iter_mut().zip(&tails) {
+            *row = match tail {
+                Some(tail) if tail.end > time => Row {
+                    end: width - velocity(tail.step) * (time - tail.time) + tail.width,
+                    step: tail.step,
+                },
+                _ => Row::default(),
+            };
+        }
+        let (row, step) = match layout.scroll_row(0., span, &mut rng) {
+            Some(row) => row,
+            None if options.no_overlap => continue,
+            None => (layout.earliest_scroll_row(span), MIN_STEP),
+        };
+        let comment_width = layout.comment_width(comment, options);
+        let end = time + (width + comment_width) / velocity(step);
+        for tail in tails.iter_mut().skip(row).take(span) {
+            if tail.is_none_or(|tail| tail.end < end) {
+                *tail = Some(Tail {
+                    time,
+                    step,
+                    width: comment_width,
+                    end,
+                });
+            }
+        }
+
+        let y = layout.y(Mode::Scroll, row, span);
+        dialogue(
+            &mut ass,
+            time,
+            end,
+            &format!(
+                "\\move({},{},{},{}){}",
+                width,
+                y,
+                -comment_width,
+                y,
+                style(comment, options)
+            ),
+            &comment.text(),
+        );
+    }
+    ass
+}
+
+fn header(layout: &Layout, options: &Options) -> String {
+    format!(
+        "[Script Info]
+ScriptType: v4.00+
+PlayResX: {}
+PlayResY: {}
+ScaledBorderAndShadow: yes
+WrapStyle: 2
+
+[V4+ Styles]
+Format: Name, Fontname, Fontsize, PrimaryColour, SecondaryColour, OutlineColour, BackColour, \
+Bold, Italic, Underline, StrikeOut, ScaleX, ScaleY, Spacing, Angle, BorderStyle, Outline, \
+Shadow, Alignment, MarginL, MarginR, MarginV, Encoding
+Style: Danmaku,{},{},&H00FFFFFF,&H00FFFFFF,&H00{:06X},&H00000000,{},0,0,0,100,100,0,0,\
+1,{},{},7,0,0,0,1
+
+[Events]
+Format: Layer, Start, End, Style, Name, MarginL, MarginR, MarginV, Effect, Text
+",
+        layout.width as i64,
+        layout.height as i64,
+        if options.font_name.is_empty() {
+            "sans-serif"
+        } else {
+            &options.font_name
+        },
+        options.font_size,
+        bgr(options.outline_color),
+        if options.bold { -1 } else { 0 },
+        options.outline,
+        options.shadow,
+    )
+}
+
+fn dialogue(ass: &mut String, start: f64, end: f64, tags: &str, message: &str) {
+    _ = writeln!(
+        ass,
+        "Dialogue: 0,{},{},Danmaku,,0,0,0,,{{{}}}{}",
+        timestamp(start),
+        timestamp(end),
+        tags,
+        message
+    );
+}
+
+/// `H:MM:SS.cc`
+fn timestamp(seconds: f64) -> String {
+    let centiseconds = (seconds * 100.).round() as u64;
+    format!(
+        "{}:{:02}:{:02}.{:02}",
+        centiseconds / 360000,
+        centiseconds / 6000 % 60,
+        centiseconds / 100 % 60,
+        centiseconds % 100
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dandanplay::Source;
+
+    #[test]
+    fn test_export_ass() {
+        let comments = [
+            Danmaku::new("scroll", 1., Mode::Scroll, 0xffffff, Source::Dandan, "1"),
+            Danmaku::new("top", 3661.5, Mode::Top, 0xff0000, Source::Dandan, "2"),
+        ];
+        let ass = export_ass(&comments, 0., 1920., 1080., &Options::default());
+        let events = ass
+            .lines()
+            .filter(|line| line.starts_with("Dialogue:"))
+            .collect::<Vec<_>>();
+
+        assert_eq!(events.len(), 2);
+        assert!(events[0].starts_with("Dialogue: 0,0:00:01.00,"));
+        assert!(events[0].contains("\\move(1920,0,"));
+        assert!(events[0].ends_with("}scroll"));
+        assert!(events[1].starts_with("Dialogue: 0,1:01:01.50,1:01:06.50,"));
+        assert!(events[1].contains("\\an8\\pos(960,0)"));
+    }
+}
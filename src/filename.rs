@@ -0,0 +1,236 @@
+use regex::Regex;
+use std::sync::LazyLock;
+
+/// leading `[group]` or `【group】`
+static GROUP: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^\s*(?:\[([^\]]+)\]|【([^】]+)】)").unwrap());
+static RESOLUTION: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?i)\b(?:(\d{3,4})p|\d{3,4}x(\d{3,4})|([248])k)\b").unwrap());
+static SEASON_EPISODE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?i)\bS(\d{1,2})[ ._]?E(\d{1,4})(?:v\d)?\b").unwrap());
+/// episode markers, in the order they are tried
+static EPISODE: LazyLock<[Regex; 4]> = LazyLock::new(|| {
+    [
+        r"\s-\s(\d{1,4})(?:v\d)?(?:\s|\[|\(|$)",
+        r"[\[【](\d{1,4})(?:v\d)?(?:\s*END)?[\]】]",
+        r"第(\d{1,4})[话話集]",
+        r"(?i)\bEP?(\d{1,4})(?:v\d)?\b",
+    ]
+    .map(|pattern| Regex::new(pattern).unwrap())
+});
+static SEASON: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        r"(?i)\bS(\d{1,2})\b|\bSeason\s*(\d{1,2})\b|\b(\d{1,2})(?:st|nd|rd|th)\s+Season\b|第([一二三四五六七八九十\d]{1,3})季",
+    )
+    .unwrap()
+});
+static BRACKETS: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\[([^\]]*)\]|【([^】]*)】|\(([^)]*)\)").unwrap());
+static DECORATION: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"★[^★]*★").unwrap());
+
+/// what a typical anime release name tells about the video
+#[derive(Debug, Default, PartialEq)]
+pub struct ReleaseName {
+    pub title: String,
+    pub season: Option<u32>,
+    pub episode: Option<u32>,
+    pub group: Option<String>,
+    pub resolution: Option<String>,
+}
+
+impl ReleaseName {
+    pub fn parse(file_name: &str) -> Self {
+        // not `Path::file_stem`, titles may contain slashes
+        let stem = file_name
+            .rsplit_once('.')
+            .filter(|(_, ext)| ext.len() <= 4 && ext.chars().all(|c| c.is_ascii_alphanumeric()))
+            .map_or(file_name, |(stem, _)| stem);
+        let mut rest = DECORATION.replace_all(stem, "").into_owned();
+
+        let mut release = ReleaseName {
+            resolution: RESOLUTION.captures(&rest).map(|captures| {
+                match (captures.get(1), captures.get(2), captures.get(3)) {
+                    (Some(height), _, _) | (_, Some(height), _) => format!("{}p", height.as_str()),
+                    _ => format!("{}K", &captures[3]),
+                }
+            }),
+            ..Default::default()
+        };
+
+        if let Some(captures) = GROUP.captures(&rest) {
+            let group = captures.get(1).or(captures.get(2)).unwrap().as_str();
+            release.group = Some(group.trim().to_string());
+            rest = rest[captures.get(0).unwrap().end()..].to_string();
+        }
+
+        let title = if let Some(captures) = SEASON_EPISODE.captures(&rest) {
+            release.season = captures[1].parse().ok();
+            release.episode = captures[2].parse().ok();
+            rest[..captures.get(0).unwrap().start()].to_string()
+        } else if let Some(captures) = EPISODE.iter().find_map(|episode| episode.captures(&rest)) {
+            release.episode = captures[1].parse().ok();
+            rest[..captures.get(0).unwrap().start()].to_string()
+        } else {
+            rest
+        };
+
+        // free text before the episode, or the last bracket when everything is bracketed
+        let free = BRACKETS.replace_all(&title, " ");
+        let mut title = if free.trim().is_empty() {
+            BRACKETS
+                .captures_iter(&title)
+                .filter_map(|captures| {
+                    captures
+                        .iter()
+                        .skip(1)
+                        .flatten()
+                        .next()
+                        .map(|m| m.as_str().to_string())
+                })
+                .last()
+                .unwrap_or_default()
+        } else {
+            free.into_owned()
+        };
+        if !title.contains(' ') {
+            title = title.replace(['.', '_'], " ");
+        }
+
+        if let Some(captures) = SEASON.captures(&title) {
+            release.season = release.season.or_else(|| season_number(&captures));
+            title.replace_range(captures.get(0).unwrap().range(), "");
+        }
+
+        // alternative titles are separated by slashes, keep the first
+        let title = title.split(" / ").next().unwrap_or_default();
+        release.title = title
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ")
+            .trim_matches(|c: char| c == '-' || c.is_whitespace())
+            .to_string();
+        // `[title][01]` has no group
+        if release.title.is_empty() {
+            release.title = release.group.take().unwrap_or_default();
+        }
+        release
+    }
+}
+
+fn season_number(captures: &regex::Captures) -> Option<u32> {
+    let number = captures.iter().skip(1).flatten().next()?.as_str();
+    number.parse().ok().or_else(|| {
+        let digit = |c| {
+            "一二三四五六七八九"
+                .chars()
+                .position(|d| d == c)
+                .map(|d| d as u32 + 1)
+        };
+        match number.chars().collect::<Vec<_>>()[..] {
+            ['十'] => Some(10),
+            ['十', ones] => Some(10 + digit(ones)?),
+            [tens, '十'] => Some(digit(tens)? * 10),
+            [tens, '十', ones] => Some(digit(tens)? * 10 + digit(ones)?),
+            [ones] => digit(ones),
+            _ => None,
+        }
+    })
+}
+
+/// season an anime title on dandanplay refers to, 1 when it has no season marker
+pub fn title_season(title: &str) -> u32 {
+    SEASON
+        .captures(title)
+        .and_then(|captures| season_number(&captures))
+        .unwrap_or(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let release =
+            |title: &str, season, episode, group: Option<&str>, resolution: Option<&str>| {
+                ReleaseName {
+                    title: title.to_string(),
+                    season,
+                    episode,
+                    group: group.map(Into::into),
+                    resolution: resolution.map(Into::into),
+                }
+            };
+        assert_eq!(
+            ReleaseName::parse(
+                "[Lilith-Raws] Sousou no Frieren - 05 [Baha][WEB-DL][1080p][AVC AAC][CHT][MP4].mp4"
+            ),
+            release(
+                "Sousou no Frieren",
+                None,
+                Some(5),
+                Some("Lilith-Raws"),
+                Some("1080p")
+            )
+        );
+        assert_eq!(
+            ReleaseName::parse(
+                "[SweetSub&LoliHouse] Spy x Family S2 - 03 [WebRip 1080p HEVC-10bit AAC].mkv"
+            ),
+            release(
+                "Spy x Family",
+                Some(2),
+                Some(3),
+                Some("SweetSub&LoliHouse"),
+                Some("1080p")
+            )
+        );
+        assert_eq!(
+            ReleaseName::parse("[Nekomoe kissaten][Bocchi the Rock!][07][1080p][JPSC].mp4"),
+            release(
+                "Bocchi the Rock!",
+                None,
+                Some(7),
+                Some("Nekomoe kissaten"),
+                Some("1080p")
+            )
+        );
+        assert_eq!(
+            ReleaseName::parse("Sousou.no.Frieren.S01E05.1080p.WEB-DL.mkv"),
+            release("Sousou no Frieren", Some(1), Some(5), None, Some("1080p"))
+        );
+        assert_eq!(
+            ReleaseName::parse(
+                "【喵萌奶茶屋】★04月新番★[间谍过家家 / SPY×FAMILY][05][1080p][简日双语].mp4"
+            ),
+            release(
+                "间谍过家家",
+                None,
+                Some(5),
+                Some("喵萌奶茶屋"),
+                Some("1080p")
+            )
+        );
+        assert_eq!(
+            ReleaseName::parse("[Sakurato] 鬼灭之刃 第三季 [05][AVC-8bit 1920x1080 AAC][CHS].mp4"),
+            release(
+                "鬼灭之刃",
+                Some(3),
+                Some(5),
+                Some("Sakurato"),
+                Some("1080p")
+            )
+        );
+        assert_eq!(
+            ReleaseName::parse("Your Name (2016) [4K].mkv"),
+            release("Your Name", None, None, None, Some("4K"))
+        );
+    }
+
+    #[test]
+    fn test_title_season() {
+        assert_eq!(title_season("间谍过家家 第二季"), 2);
+        assert_eq!(title_season("Spy x Family Season 2"), 2);
+        assert_eq!(title_season("葬送的芙莉莲"), 1);
+    }
+}
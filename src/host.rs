@@ -0,0 +1,166 @@
+use anyhow::Result;
+use std::sync::{Arc, RwLock};
+
+/// events the plugin reacts to, decoupled from the raw `mpv_event`
+#[derive(Clone, Debug, PartialEq)]
+pub enum Event {
+    /// `wait_event` timed out
+    Timeout,
+    Shutdown,
+    FileLoaded,
+    PlaybackRestart,
+    PropertyChange(Property),
+    ClientMessage(Vec<String>),
+    /// anything the plugin does not handle
+    Other,
+}
+
+/// observed properties
+#[derive(Clone, Debug, PartialEq)]
+pub enum Property {
+    Pause(bool),
+    DisplayFps(f64),
+    OsdWidth(f64),
+    OsdHeight(f64),
+    SubText(String),
+    SubVisibility(bool),
+    SecondarySubText(String),
+    SecondarySubVisibility(bool),
+    SubFontSize(f64),
+    SubMarginY(f64),
+    SubPos(f64),
+    /// every `key=value` pair of the `script-opts` property
+    ScriptOpts(Vec<(String, String)>),
+}
+
+/// the player the plugin runs in
+pub trait Host: Send + Sync {
+    fn wait_event(&self, timeout: f64) -> Event;
+    /// interrupt a pending `wait_event`
+    fn wakeup(&self);
+    fn get_property_f64(&self, name: &str) -> Option<f64>;
+    fn get_property_string(&self, name: &str) -> Option<String>;
+    fn command(&self, args: &[&str]);
+    fn expand_path(&self, path: &str) -> Result<String>;
+    fn osd_message(&self, text: &str);
+    fn osd_overlay(&self, id: i64, data: &str, width: i64, height: i64);
+    fn remove_overlay(&self, id: i64);
+}
+
+static HOST: RwLock<Option<Arc<dyn Host>>> = RwLock::new(None);
+
+pub fn host() -> Arc<dyn Host> {
+    HOST.read().unwrap().clone().expect("host not set")
+}
+
+pub fn set_host(host: Arc<dyn Host>) {
+    *HOST.write().unwrap() = Some(host);
+}
+
+#[cfg(test)]
+pub mod fake {
+    use super::*;
+    use std::{
+        collections::{HashMap, VecDeque},
+        env::temp_dir,
+        sync::Mutex,
+    };
+
+    /// the host, the options and the caches are global, tests using them run one at a time
+    pub static LOCK: Mutex<()> = Mutex::new(());
+
+    enum Step {
+        Event(Event),
+        Property(String, String),
+    }
+
+    /// in-memory player which replays queued events and records the output
+    #[derive(Default)]
+    pub struct FakeHost {
+        steps: Mutex<VecDeque<Step>>,
+        properties: Mutex<HashMap<String, String>>,
+        overlays: Mutex<Vec<(i64, String)>>,
+        pub messages: Mutex<Vec<String>>,
+        pub commands: Mutex<Vec<Vec<String>>>,
+    }
+
+    impl FakeHost {
+        pub fn push(&self, event: Event) {
+            self.steps.lock().unwrap().push_back(Step::Event(event));
+        }
+
+        /// set a property once the events queued before are consumed
+        pub fn push_property(&self, name: &str, value: impl ToString) {
+            self.steps
+                .lock()
+                .unwrap()
+                .push_back(Step::Property(name.to_string(), value.to_string()));
+        }
+
+        pub fn set_property(&self, name: &str, value: impl ToString) {
+            self.properties
+                .lock()
+                .unwrap()
+                .insert(name.to_string(), value.to_string());
+        }
+
+        /// every content an overlay was set to, removals are recorded as empty
+        pub fn frames(&self, id: i64) -> Vec<String> {
+            self.overlays
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|(i, _)| *i == id)
+                .map(|(_, data)| data.clone())
+                .collect()
+        }
+    }
+
+    impl Host for FakeHost {
+        /// the next queued event, `Shutdown` once the queue is drained
+        fn wait_event(&self, _timeout: f64) -> Event {
+            let mut steps = self.steps.lock().unwrap();
+            while let Some(step) = steps.pop_front() {
+                match step {
+                    Step::Event(event) => return event,
+                    Step::Property(name, value) => self.set_property(&name, value),
+                }
+            }
+            Event::Shutdown
+        }
+
+        fn wakeup(&self) {}
+
+        fn get_property_f64(&self, name: &str) -> Option<f64> {
+            self.properties.lock().unwrap().get(name)?.parse().ok()
+        }
+
+        fn get_property_string(&self, name: &str) -> Option<String> {
+            self.properties.lock().unwrap().get(name).cloned()
+        }
+
+        fn command(&self, args: &[&str]) {
+            self.commands
+                .lock()
+                .unwrap()
+                .push(args.iter().map(ToString::to_string).collect());
+        }
+
+        fn expand_path(&self, path: &str) -> Result<String> {
+            let root = temp_dir().join("mpv-danmaku-test");
+            Ok(path.replacen("~~", &root.to_string_lossy(), 1))
+        }
+
+        fn osd_message(&self, text: &str) {
+            self.messages.lock().unwrap().push(text.to_string());
+        }
+
+        fn osd_overlay(&self, id: i64, data: &str, _width: i64, _height: i64) {
+            self.overlays.lock().unwrap().push((id, data.to_string()));
+        }
+
+        fn remove_overlay(&self, id: i64) {
+            self.overlays.lock().unwrap().push((id, String::new()));
+        }
+    }
+}
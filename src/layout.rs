@@ -0,0 +1,420 @@
+use crate::{
+    dandanplay::{Danmaku, Mode, Source},
+    options::Options,
+};
+use rand::Rng;
+use std::fmt::Write;
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
+
+pub const MAX_DURATION: f64 = 12.;
+pub const INTERVAL: f64 = 0.005;
+pub const MIN_STEP: f64 = INTERVAL / MAX_DURATION;
+pub const MAX_STEP: f64 = MIN_STEP * 1.3;
+pub const FIXED_DURATION: f64 = 5.;
+/// largest font size of merged comments relative to `font_size`
+pub const MAX_MERGE_SCALE: f64 = 2.;
+/// average advance of half-width glyphs such as latin letters and half-width kana in em
+pub const HALF_WIDTH: f64 = 0.6;
+
+#[derive(Clone, Copy)]
+pub struct Row {
+    pub end: f64,
+    pub step: f64,
+}
+
+impl Default for Row {
+    fn default() -> Self {
+        Row {
+            end: 0.,
+            step: MIN_STEP,
+        }
+    }
+}
+
+/// lane state shared by the renderer and the ASS exporter
+pub struct Layout {
+    pub width: f64,
+    pub height: f64,
+    pub spacing: f64,
+    pub row_height: f64,
+    pub bottom: f64,
+    /// vertical ranges no new comment is placed in
+    pub blocked: Vec<(f64, f64)>,
+    pub rows: Vec<Row>,
+    pub top_rows: Vec<f64>,
+    pub bottom_rows: Vec<f64>,
+}
+
+impl Layout {
+    pub fn new(osd_width: f64, osd_height: f64, options: &Options) -> Self {
+        let mut width = 1920.;
+        let mut height = 1080.;
+        let ratio = osd_width / osd_height;
+        if width / height < ratio {
+            height = width / ratio;
+        } else if width / height > ratio {
+            width = height * ratio;
+        }
+        let spacing = options.font_size / 10.;
+        let bottom = height * (1. - options.reserved_space);
+        let num_rows = ((bottom / (options.font_size + spacing)) as usize).max(1);
+        // only scrolling comments are kept to the top of the screen
+        let scroll_rows = ((num_rows as f64 * options.density_area).ceil() as usize).max(1);
+        Layout {
+            width,
+            height,
+            spacing,
+            row_height: options.font_size + spacing,
+            bottom,
+            blocked: Vec::new(),
+            rows: vec![Row::default(); scroll_rows],
+            top_rows: vec![0.; num_rows],
+            bottom_rows: vec![0.; num_rows],
+        }
+    }
+
+    /// width of a scrolling comment including the gap to the next one
+    pub fn comment_width(&self, comment: &Danmaku, options: &Options) -> f64 {
+        comment.text_width() * font_size(comment, options) + self.spacing
+    }
+
+    /// rows taken by a comment, at most the rows of its mode
+    pub fn span(&self, comment: &Danmaku, options: &Options) -> usize {
+        let rows = match comment.mode {
+            Mode::Scroll => self.rows.len(),
+            Mode::Top => self.top_rows.len(),
+            Mode::Bottom => self.bottom_rows.len(),
+        };
+        (((font_size(comment, options) + self.spacing) / self.row_height).ceil() as usize)
+            .clamp(1, rows)
+    }
+
+    /// top of a comment taking `span` rows from `row`, bottom rows count upwards
+    pub fn y(&self, mode: Mode, row: usize, span: usize) -> f64 {
+        match mode {
+            Mode::Bottom => self.bottom - (row + span) as f64 * self.row_height,
+            Mode::Scroll | Mode::Top => row as f64 * self.row_height,
+        }
+    }
+
+    /// keep new comments out of the area between `top` and `bottom`
+    pub fn block(&mut self, top: f64, bottom: f64) {
+        self.blocked.push((top, bottom));
+    }
+
+    /// whether a comment taking `span` rows from `row` overlaps a blocked area
+    pub fn is_blocked(&self, mode: Mode, row: usize, span: usize) -> bool {
+        let y = self.y(mode, row, span);
+        let height = span as f64 * self.row_height;
+        self.blocked
+            .iter()
+            .any(|&(top, bottom)| y < bottom && y + height > top)
+    }
+
+    /// x position of a scrolling comment `elapsed` seconds of playback after it entered the
+    /// screen
+    pub fn scroll_x(&self, step: f64, elapsed: f64, options: &Options) -> f64 {
+        self.width - self.width * step * ticks(elapsed, options)
+    }
+
+    /// seconds of playback a scrolling comment `comment_width` wide takes to cross the screen
+    /// at the slowest step
+    pub fn scroll_duration(&self, comment_width: f64, options: &Options) -> f64 {
+        (self.width + comment_width) / (self.width * MIN_STEP) * INTERVAL / options.speed
+    }
+
+    /// first `span` scrolling rows a comment that entered `ticks` ticks ago fits in, and a
+    /// step slow enough not to catch up with the comments ahead of it
+    pub fn scroll_row(&self, ticks: f64, span: usize, rng: &mut impl Rng) -> Option<(usize, f64)> {
+        let width = self.width;
+        for (row, rows) in self.rows.windows(span).enumerate() {
+            if self.is_blocked(Mode::Scroll, row, span) {
+                continue;
+            }
+            // empty rows also fit comments already partly past the left edge
+            if rows
+                .iter()
+                .all(|status| status.end == 0. || status.end < width - width * ticks * MIN_STEP)
+            {
+                let max_step = rows
+                    .iter()
+                    .filter(|status| status.end != 0.)
+                    // 1 / max_step - ticks = status.end / width / status.step
+                    .map(|status| 1. / (ticks + status.end / width / status.step))
+                    .fold(MAX_STEP, f64::min);
+                return Some((row, rng.random_range(MIN_STEP..max_step)));
+            }
+        }
+        None
+    }
+
+    /// `span` scrolling rows whose last comment is the closest to the left edge, blocked rows
+    /// are only used when every row is blocked
+    pub fn earliest_scroll_row(&self, span: usize) -> usize {
+        let key = |row: usize| {
+            let end = self.rows[row..row + span]
+                .iter()
+                .map(|status| status.end)
+                .fold(f64::MIN, f64::max);
+            (self.is_blocked(Mode::Scroll, row, span), end)
+        };
+        (0..=self.rows.len() - span)
+            .min_by(|&a, &b| key(a).partial_cmp(&key(b)).unwrap())
+            .unwrap()
+    }
+
+    /// keep track of the scrolling comment which leaves each of the `span` rows last
+    pub fn occupy_scroll_row(&mut self, row: usize, span: usize, end: f64, step: f64) {
+        for row in self.rows.iter_mut().skip(row).take(span) {
+            if end / step > row.end / row.step {
+                *row = Row { end, step };
+            }
+        }
+    }
+
+    pub fn fixed_rows(&mut self, mode: Mode) -> &mut Vec<f64> {
+        if mode == Mode::Top {
+            &mut self.top_rows
+        } else {
+            &mut self.bottom_rows
+        }
+    }
+
+    /// first `span` fixed rows free at `time`, or the ones which free up first when
+    /// overlapping, blocked rows are only used then and when every row is blocked
+    pub fn fixed_row(&self, mode: Mode, time: f64, span: usize, overlap: bool) -> Option<usize> {
+        let rows = if mode == Mode::Top {
+            &self.top_rows
+        } else {
+            &self.bottom_rows
+        };
+        let ends = rows
+            .windows(span)
+            .map(|rows| rows.iter().copied().fold(f64::MIN, f64::max))
+            .enumerate()
+            .map(|(row, end)| (row, (self.is_blocked(mode, row, span), end)));
+        match ends
+            .clone()
+            .find(|&(_, (blocked, end))| !blocked && end <= time)
+        {
+            Some((row, _)) => Some(row),
+            None if overlap => ends
+                .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap())
+                .map(|(row, _)| row),
+            None => None,
+        }
+    }
+
+    pub fn occupy_fixed_row(&mut self, mode: Mode, row: usize, span: usize, end: f64) {
+        for fixed_end in self.fixed_rows(mode).iter_mut().skip(row).take(span) {
+            *fixed_end = fixed_end.max(end);
+        }
+    }
+}
+
+/// elapsed playback time in ticks of `INTERVAL`, scaled by the speed option
+pub fn ticks(elapsed: f64, options: &Options) -> f64 {
+    elapsed * options.speed / INTERVAL
+}
+
+/// comments with a higher score are kept first when the density is limited, favoring
+/// popular, colored, short and native comments
+pub fn priority(comment: &Danmaku) -> f64 {
+    let source = match comment.source {
+        Source::Dandan => 1.,
+        Source::Bilibili => 0.5,
+        _ => 0.,
+    };
+    let colored = if (comment.r, comment.g, comment.b) == (0xff, 0xff, 0xff) {
+        0.
+    } else {
+        0.5
+    };
+    (comment.repeat - 1) as f64 + source + colored - comment.count as f64 / 50.
+}
+
+/// estimated width of `text` in em, full-width characters and emoji take 1 em, half-width
+/// ones `HALF_WIDTH`, multiple lines take the width of the longest one
+pub fn text_width(text: &str) -> f64 {
+    text.lines()
+        .map(|line| {
+            line.graphemes(true)
+                .map(|grapheme| match grapheme.width() {
+                    0 => 0.,
+                    1 => HALF_WIDTH,
+                    // emoji sequences are drawn as a single glyph
+                    _ => 1.,
+                })
+                .sum::<f64>()
+        })
+        .fold(0., f64::max)
+}
+
+/// font size of a comment relative to its size in the source, grown by `merge_boost` for
+/// every merged duplicate
+pub fn font_size(comment: &Danmaku, options: &Options) -> f64 {
+    let scale = 1. + options.merge_boost * (comment.repeat - 1) as f64;
+    options.font_size * comment.size * scale.min(MAX_MERGE_SCALE)
+}
+
+/// ASS override tags for color, transparency, font and border of a comment
+pub fn style(comment: &Danmaku, options: &Options) -> String {
+    let mut tags = format!(
+        "\\c&H{:02x}{:02x}{:02x}&\\alpha&H{:02x}\\fs{}",
+        comment.b,
+        comment.g,
+        comment.r,
+        options.transparency,
+        font_size(comment, options),
+    );
+    if !options.font_name.is_empty() {
+        _ = write!(tags, "\\fn{}", options.font_name);
+    }
+    _ = write!(
+        tags,
+        "\\bord{}\\3c&H{:06x}&\\shad{}\\b{}\\q2{}",
+        options.outline,
+        bgr(options.outline_color),
+        options.shadow,
+        u8::from(options.bold),
+        match comment.mode {
+            Mode::Scroll => &options.scroll_tags,
+            Mode::Top => &options.top_tags,
+            Mode::Bottom => &options.bottom_tags,
+        }
+    );
+    tags
+}
+
+/// `0xRRGGBB` to the `0xBBGGRR` order of ASS colors
+pub fn bgr(rgb: u32) -> u32 {
+    (rgb & 0xff) << 16 | (rgb & 0xff00) | (rgb >> 16 & 0xff)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_style() {
+        let comment = Danmaku::new("top", 0., Mode::Top, 0x0a0b0c, Source::Dandan, "1");
+        assert_eq!(
+            style(&comment, &Options::default()),
+            "\\c&H0c0b0a&\\alpha&H30\\fs40\\bord1.5\\3c&H000000&\\shad0\\b1\\q2"
+        );
+
+        let options = Options {
+            font_name: "Noto Sans CJK SC".into(),
+            bold: false,
+            outline: 1.,
+            outline_color: 0x112233,
+            shadow: 2.,
+            top_tags: "\\blur1".into(),
+            ..Default::default()
+        };
+        assert_eq!(
+            style(&comment, &options),
+            "\\c&H0c0b0a&\\alpha&H30\\fs40\\fnNoto Sans CJK SC\\bord1\\3c&H332211&\\shad2\\b0\\q2\\blur1"
+        );
+    }
+
+    #[test]
+    fn test_fixed_row() {
+        let mut layout = Layout::new(1920., 1080., &Options::default());
+        // free lanes are taken from the edge inwards
+        assert_eq!(layout.fixed_row(Mode::Top, 0., 1, false), Some(0));
+        layout.occupy_fixed_row(Mode::Top, 0, 1, 5.);
+        assert_eq!(layout.fixed_row(Mode::Top, 1., 1, false), Some(1));
+        layout.occupy_fixed_row(Mode::Top, 1, 1, 6.);
+        // reused once the comment in it expired
+        assert_eq!(layout.fixed_row(Mode::Top, 5., 1, false), Some(0));
+
+        // bottom lanes stack upwards from the bottom edge
+        layout.occupy_fixed_row(Mode::Bottom, 0, 1, 5.);
+        let row = layout.fixed_row(Mode::Bottom, 1., 1, false).unwrap();
+        assert_eq!(row, 1);
+        assert_eq!(layout.y(Mode::Bottom, 0, 1), 1080. - layout.row_height);
+        assert_eq!(
+            layout.y(Mode::Bottom, row, 1),
+            1080. - 2. * layout.row_height
+        );
+        assert_eq!(layout.y(Mode::Top, 1, 1), layout.row_height);
+
+        // every lane taken, the one freeing up first is shared when overlapping
+        let rows = layout.top_rows.len();
+        for row in 0..rows {
+            layout.occupy_fixed_row(Mode::Top, row, 1, 10. + row as f64);
+        }
+        assert_eq!(layout.fixed_row(Mode::Top, 9., 1, false), None);
+        assert_eq!(layout.fixed_row(Mode::Top, 9., 1, true), Some(0));
+    }
+
+    #[test]
+    fn test_text_width() {
+        assert_eq!(text_width("ab"), 2. * HALF_WIDTH);
+        assert_eq!(text_width("弹幕"), 2.);
+        assert_eq!(text_width("ｱｲ"), 2. * HALF_WIDTH);
+        assert_eq!(text_width("👨‍👩‍👧👍🏻"), 2.);
+        assert_eq!(text_width("ab\nc"), 2. * HALF_WIDTH);
+        assert_eq!(text_width(""), 0.);
+    }
+
+    #[test]
+    fn test_span() {
+        let options = Options::default();
+        let mut layout = Layout::new(1920., 1080., &options);
+        let mut big = Danmaku::new("big", 0., Mode::Bottom, 0, Source::Bilibili, "1");
+        big.size = 36. / 25.;
+        let small = Danmaku::new("big", 0., Mode::Bottom, 0, Source::Bilibili, "1");
+        assert_eq!(layout.span(&small, &options), 1);
+        assert_eq!(layout.span(&big, &options), 2);
+        assert!(layout.comment_width(&big, &options) > layout.comment_width(&small, &options));
+
+        layout.occupy_fixed_row(Mode::Bottom, 0, 2, 5.);
+        assert_eq!(layout.fixed_row(Mode::Bottom, 0., 1, false), Some(2));
+        assert_eq!(layout.y(Mode::Bottom, 0, 2), 1080. - 2. * layout.row_height);
+
+        let mut blocked = Layout::new(1920., 1080., &options);
+        blocked.block(0., 1080.);
+        blocked.occupy_fixed_row(Mode::Top, 0, 1, 5.);
+        assert_eq!(blocked.fixed_row(Mode::Top, 0., 1, false), None);
+        assert_eq!(blocked.fixed_row(Mode::Top, 0., 1, true), Some(1));
+
+        layout.rows[1] = Row {
+            end: 1920.,
+            step: MIN_STEP,
+        };
+        let (row, _) = layout.scroll_row(0., 2, &mut rand::rng()).unwrap();
+        assert_eq!(row, 2);
+    }
+
+    #[test]
+    fn test_density_area() {
+        let options = Options {
+            density_area: 0.25,
+            ..Default::default()
+        };
+        let layout = Layout::new(1920., 1080., &options);
+        // 24 rows of 44 pixels
+        assert_eq!(layout.rows.len(), 6);
+        assert_eq!(layout.top_rows.len(), 24);
+        assert_eq!(layout.bottom_rows.len(), 24);
+
+        // fixed comments are not limited by the scrolling area
+        let layout = Layout::new(
+            1920.,
+            1080.,
+            &Options {
+                density_area: 0.01,
+                ..options.clone()
+            },
+        );
+        assert_eq!(layout.rows.len(), 1);
+        let mut big = Danmaku::new("big", 0., Mode::Top, 0, Source::Bilibili, "1");
+        big.size = 36. / 25.;
+        assert_eq!(layout.span(&big, &options), 2);
+        big.mode = Mode::Scroll;
+        assert_eq!(layout.span(&big, &options), 1);
+    }
+}
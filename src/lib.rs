@@ -1,33 +1,41 @@
+pub mod bilibili;
+pub mod chinese;
 pub mod dandanplay;
 pub mod emby;
+pub mod export;
 pub mod ffi;
+pub mod filename;
+pub mod host;
+pub mod layout;
 pub mod log;
+pub mod menu;
 pub mod mpv;
 pub mod options;
 pub mod service;
 pub mod utils;
 
 use crate::{
-    dandanplay::{Danmaku, Source, Status, StatusInner, get_danmaku},
-    ffi::{
-        mpv_client_name, mpv_event_client_message, mpv_event_id, mpv_event_property, mpv_format,
-        mpv_handle, mpv_node, mpv_observe_property, mpv_wait_event, mpv_wakeup,
+    dandanplay::{
+        Danmaku, Mode, Status, StatusInner, add_source, filter_danmaku, get_danmaku,
+        refresh_danmaku, search_danmaku, toggle_source,
     },
+    ffi::{mpv_client_name, mpv_format, mpv_handle, mpv_observe_property},
+    host::{Event, Property, host, set_host},
+    layout::{FIXED_DURATION, INTERVAL, Layout, MIN_STEP, priority, style, ticks},
     log::{log_code, log_error},
-    mpv::{get_property_f64, get_property_string, osd_message, osd_overlay, remove_overlay},
+    mpv::{Mpv, get_property_f64, get_property_string, osd_message, osd_overlay, remove_overlay},
     options::{Filter, Options},
-    service::DandanplayService,
+    utils::is_http_link,
 };
 use anyhow::anyhow;
 use mpv::expand_path;
-use rand::{Rng, rng};
+use rand::rng;
 use std::{
-    collections::HashSet,
     ffi::CStr,
     fs,
+    ops::{Deref, DerefMut, Range},
     os::raw::c_int,
     ptr::null_mut,
-    slice::from_raw_parts,
     sync::{
         Arc, LazyLock, OnceLock,
         atomic::{AtomicBool, Ordering},
@@ -40,23 +48,76 @@ use tracing_appender::rolling::{RollingFileAppender, Rotation};
 use tracing_subscriber::fmt::time::ChronoUtc;
 use tracing_subscriber::{EnvFilter, fmt, layer::SubscriberExt, util::SubscriberInitExt};
 
-const MAX_DURATION: f64 = 12.;
-const INTERVAL: f64 = 0.005;
-const MIN_STEP: f64 = INTERVAL / MAX_DURATION;
-const MAX_STEP: f64 = MIN_STEP * 1.3;
-
 pub static mut CTX: *mut mpv_handle = null_mut();
 pub static CLIENT_NAME: OnceLock<String> = OnceLock::new();
 
 static ENABLED: AtomicBool = AtomicBool::new(false);
-static COMMENTS: LazyLock<Mutex<Option<Vec<Danmaku>>>> = LazyLock::new(|| Mutex::new(None));
+static COMMENTS: LazyLock<Mutex<Option<Comments>>> = LazyLock::new(|| Mutex::new(None));
+
+/// time sorted comments with the index of the first one which may still be on screen,
+/// `None` after a seek
+struct Comments {
+    danmaku: Vec<Danmaku>,
+    cursor: Option<usize>,
+}
+
+impl Comments {
+    fn new(danmaku: Vec<Danmaku>) -> Self {
+        Comments {
+            danmaku,
+            cursor: None,
+        }
+    }
+}
+
+impl Deref for Comments {
+    type Target = Vec<Danmaku>;
 
-#[derive(Default, Clone, Copy)]
+    fn deref(&self) -> &Self::Target {
+        &self.danmaku
+    }
+}
+
+impl DerefMut for Comments {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.danmaku
+    }
+}
+
+#[derive(Clone, Copy)]
 struct Params {
     delay: f64,
-    speed: f64,
+    display_fps: f64,
     osd_width: f64,
     osd_height: f64,
+    /// lines of the subtitle currently shown, 0 when hidden
+    sub_lines: usize,
+    sub_visible: bool,
+    secondary_sub_lines: usize,
+    secondary_sub_visible: bool,
+    /// subtitle style, sizes are relative to a 720 pixels high screen
+    sub_font_size: f64,
+    sub_margin_y: f64,
+    sub_pos: f64,
+}
+
+impl Default for Params {
+    fn default() -> Self {
+        Params {
+            delay: 0.,
+            display_fps: 0.,
+            osd_width: 0.,
+            osd_height: 0.,
+            sub_lines: 0,
+            sub_visible: false,
+            secondary_sub_lines: 0,
+            secondary_sub_visible: false,
+            // mpv defaults
+            sub_font_size: 55.,
+            sub_margin_y: 22.,
+            sub_pos: 100.,
+        }
+    }
 }
 
 #[unsafe(no_mangle)]
@@ -70,6 +131,7 @@ extern "C" fn mpv_open_cplugin(ctx: *mut mpv_handle) -> c_int {
                 .to_string()
         });
     }
+    set_host(Arc::new(Mpv));
 
     Builder::new_multi_thread()
         .enable_all()
@@ -82,9 +144,16 @@ async fn main() -> c_int {
     for (name, format) in [
         (c"script-opts", mpv_format::MPV_FORMAT_NODE),
         (c"pause", mpv_format::MPV_FORMAT_FLAG),
-        (c"speed", mpv_format::MPV_FORMAT_DOUBLE),
+        (c"display-fps", mpv_format::MPV_FORMAT_DOUBLE),
         (c"osd-width", mpv_format::MPV_FORMAT_DOUBLE),
         (c"osd-height", mpv_format::MPV_FORMAT_DOUBLE),
+        (c"sub-text", mpv_format::MPV_FORMAT_STRING),
+        (c"sub-visibility", mpv_format::MPV_FORMAT_FLAG),
+        (c"secondary-sub-text", mpv_format::MPV_FORMAT_STRING),
+        (c"secondary-sub-visibility", mpv_format::MPV_FORMAT_FLAG),
+        (c"sub-font-size", mpv_format::MPV_FORMAT_DOUBLE),
+        (c"sub-margin-y", mpv_format::MPV_FORMAT_DOUBLE),
+        (c"sub-pos", mpv_format::MPV_FORMAT_DOUBLE),
     ] {
         let error = unsafe { mpv_observe_property(CTX, 0, name.as_ptr(), format) };
         if error < 0 {
@@ -93,21 +162,9 @@ async fn main() -> c_int {
         }
     }
 
-    let options = *options::OPTIONS;
-
-    // Check authentication configuration at startup
-    if !DandanplayService::is_auth_configured() {
-        let warning = "Danmaku: app_id and app_secret not configured. Please set them in config file to use danmaku features.";
-        tracing::warn!("{}", warning);
-        osd_message(warning);
-    }
-
-    let filter = options::read_options()
-        .map_err(|e| log_error(&e))
-        .ok()
-        .flatten()
-        .unwrap_or_default()
-        .1;
+    let (options, filter, errors) = options::load(&[]);
+    options::set(options.clone());
+    report_errors(&errors);
 
     // Initialize tracing subscriber
     if ["true", "on", "enable"].contains(&options.log.to_ascii_lowercase().as_str()) {
@@ -139,139 +196,108 @@ async fn main() -> c_int {
             .init();
     }
 
+    run(options, Arc::new(filter)).await
+}
+
+/// handle events until mpv shuts down
+async fn run(mut options: Options, mut filter: Arc<Filter>) -> c_int {
     let mut handle = spawn(async {});
     let mut params = Params::default();
     let mut pause = true;
+    let mut overrides = Vec::new();
+    // set through `danmaku-density`, kept when the options are reloaded
+    let mut density = None;
     loop {
         let timeout = if !pause && ENABLED.load(Ordering::SeqCst) {
-            INTERVAL
+            // redraw once per display refresh
+            if params.display_fps > 0. {
+                1. / params.display_fps
+            } else {
+                INTERVAL
+            }
         } else {
             -1.
         };
-        let event = unsafe { &*mpv_wait_event(CTX, timeout) };
-        match event.event_id {
-            mpv_event_id::MPV_EVENT_SHUTDOWN => {
+        match host().wait_event(timeout) {
+            Event::Shutdown => {
                 handle.abort();
                 return 0;
             }
-            mpv_event_id::MPV_EVENT_FILE_LOADED => {
+            Event::FileLoaded => {
                 handle.abort();
+                menu::close().await;
                 *COMMENTS.lock().await = None;
                 params.delay = 0.;
                 if ENABLED.load(Ordering::SeqCst) {
-                    remove_overlay();
+                    remove_overlay(0);
                     handle = spawn(get(filter.clone()));
                 }
             }
-            mpv_event_id::MPV_EVENT_PLAYBACK_RESTART => {
-                if ENABLED.load(Ordering::SeqCst) {
-                    if let Some(comments) = &mut *COMMENTS.lock().await {
-                        reset_status(comments);
-                        render(comments, params, options);
-                    }
+            Event::PlaybackRestart if ENABLED.load(Ordering::SeqCst) => {
+                if let Some(comments) = &mut *COMMENTS.lock().await {
+                    reset_status(comments);
+                    render(comments, params, &options);
                 }
             }
-            mpv_event_id::MPV_EVENT_PROPERTY_CHANGE => 'a: {
-                let data = unsafe { &*(event.data as *mut mpv_event_property) };
-                if data.format == mpv_format::MPV_FORMAT_NONE {
-                    break 'a;
+            Event::PropertyChange(Property::Pause(value)) => pause = value,
+            Event::PropertyChange(Property::DisplayFps(value)) => params.display_fps = value,
+            Event::PropertyChange(Property::OsdWidth(value)) => params.osd_width = value,
+            Event::PropertyChange(Property::OsdHeight(value)) => params.osd_height = value,
+            Event::PropertyChange(Property::SubText(text)) => params.sub_lines = lines(&text),
+            Event::PropertyChange(Property::SubVisibility(value)) => params.sub_visible = value,
+            Event::PropertyChange(Property::SecondarySubText(text)) => {
+                params.secondary_sub_lines = lines(&text)
+            }
+            Event::PropertyChange(Property::SecondarySubVisibility(value)) => {
+                params.secondary_sub_visible = value
+            }
+            Event::PropertyChange(Property::SubFontSize(value)) => params.sub_font_size = value,
+            Event::PropertyChange(Property::SubMarginY(value)) => params.sub_margin_y = value,
+            Event::PropertyChange(Property::SubPos(value)) => params.sub_pos = value,
+            Event::PropertyChange(Property::ScriptOpts(values)) => {
+                let prefix = format!("{}-", CLIENT_NAME.get().unwrap_or(&"".to_string()));
+                let values = values
+                    .into_iter()
+                    .filter_map(|(k, v)| Some((k.strip_prefix(&prefix)?.to_string(), v)))
+                    .collect::<Vec<_>>();
+                if values == overrides {
+                    continue;
                 }
-                let name = unsafe { CStr::from_ptr(data.name) };
-                if name == c"pause" {
-                    pause = unsafe { *(data.data as *mut c_int) } != 0;
-                } else if name == c"osd-width" {
-                    params.osd_width = unsafe { *(data.data as *mut f64) };
-                } else if name == c"osd-height" {
-                    params.osd_height = unsafe { *(data.data as *mut f64) };
-                } else if name == c"script-opts" {
-                    let data = unsafe { &*(data.data as *mut mpv_node) };
-                    assert_eq!(data.format, mpv_format::MPV_FORMAT_NODE_MAP);
-                    let list = unsafe { &*data.u.list };
-                    if list.num == 0 {
-                        break 'a;
-                    }
-                    let num = list.num.try_into().unwrap();
-                    let keys = unsafe { from_raw_parts(list.keys, num) };
-                    let values = unsafe { from_raw_parts(list.values, num) };
-                    for (key, value) in keys.iter().zip(values) {
-                        if unsafe { CStr::from_ptr(key.cast()) }
-                            .to_str()
-                            .is_ok_and(|key| {
-                                key == format!(
-                                    "{}-filter_source",
-                                    CLIENT_NAME.get().unwrap_or(&"".to_string())
-                                )
-                            })
-                        {
-                            assert_eq!(value.format, mpv_format::MPV_FORMAT_STRING);
-                            match unsafe { CStr::from_ptr(value.u.string) }.to_str() {
-                                Ok(value) => {
-                                    *filter.sources_rt.lock().await = if value.is_empty() {
-                                        if let Some(comments) = &mut *COMMENTS.lock().await {
-                                            for comment in comments.iter_mut() {
-                                                comment.blocked =
-                                                    filter.sources.contains(&comment.source);
-                                                comment.status = Status::Uninitialized;
-                                            }
-                                            if ENABLED.load(Ordering::SeqCst) {
-                                                render(comments, params, options);
-                                            }
-                                        }
-                                        osd_message(&format!(
-                                            "Danmaku: blocked danmaku from {:?}",
-                                            filter.sources
-                                        ));
-                                        None
-                                    } else {
-                                        let sources = value
-                                            .split(',')
-                                            .map(Into::into)
-                                            .filter(|&s| s != Source::Unknown)
-                                            .collect::<HashSet<_>>();
-                                        if let Some(comments) = &mut *COMMENTS.lock().await {
-                                            for comment in comments.iter_mut() {
-                                                comment.blocked = sources.contains(&comment.source);
-                                                comment.status = Status::Uninitialized;
-                                            }
-                                            if ENABLED.load(Ordering::SeqCst) {
-                                                render(comments, params, options);
-                                            }
-                                        }
-                                        osd_message(&format!(
-                                            "Danmaku: blocked danmaku from {:?}",
-                                            sources
-                                        ));
-                                        Some(sources)
-                                    }
-                                }
-                                Err(error) => log_error(&error.into()),
-                            }
-                            break;
-                        }
+                overrides = values;
+
+                let (mut new_options, new_filter, errors) = options::load(&overrides);
+                if let Some(density) = density {
+                    new_options.density = density;
+                }
+                options::set(new_options.clone());
+                options = new_options;
+                filter = Arc::new(new_filter);
+                if let Some(comments) = &mut *COMMENTS.lock().await {
+                    filter_danmaku(comments, &filter, &options);
+                    reset_status(comments);
+                    if ENABLED.load(Ordering::SeqCst) {
+                        render(comments, params, &options);
                     }
-                } else if name == c"speed" {
-                    params.speed = unsafe { *(data.data as *mut f64) };
                 }
-            }
-            mpv_event_id::MPV_EVENT_CLIENT_MESSAGE => 'a: {
-                let data = unsafe { &*(event.data as *mut mpv_event_client_message) };
-                if data.args.is_null() {
-                    break 'a;
+                if errors.is_empty() {
+                    osd_message("Danmaku: options updated");
+                } else {
+                    report_errors(&errors);
                 }
-                if let [arg1, args @ ..] =
-                    unsafe { from_raw_parts(data.args, data.num_args.try_into().unwrap()) }
-                {
-                    let arg1 = unsafe { CStr::from_ptr(*arg1) };
-                    if arg1 == c"toggle-danmaku" {
+            }
+            Event::ClientMessage(message) => {
+                if let [arg1, args @ ..] = message.as_slice() {
+                    if arg1 == "toggle-danmaku" {
                         if ENABLED.fetch_not(Ordering::SeqCst) {
                             handle.abort();
-                            remove_overlay();
+                            menu::close().await;
+                            remove_overlay(0);
                             osd_message("Danmaku: off");
                         } else {
                             match &mut *COMMENTS.lock().await {
                                 Some(comments) => {
                                     reset_status(comments);
-                                    render(comments, params, options);
+                                    render(comments, params, &options);
                                     loaded(comments.iter().filter(|c| !c.blocked).count());
                                 }
                                 None => {
@@ -280,32 +306,125 @@ async fn main() -> c_int {
                                 }
                             }
                         }
-                    } else if arg1 == c"danmaku-delay" {
+                    } else if arg1 == "danmaku-menu" {
                         match args.first() {
-                            Some(&seconds) => {
-                                match unsafe { CStr::from_ptr(seconds) }
-                                    .to_str()
-                                    .ok()
-                                    .and_then(|s| s.parse::<f64>().ok())
-                                {
-                                    Some(seconds) => {
-                                        params.delay += seconds;
-                                        if ENABLED.load(Ordering::SeqCst) {
-                                            if let Some(comments) = &mut *COMMENTS.lock().await {
-                                                reset_status(comments);
-                                                render(comments, params, options);
-                                            }
-                                        }
-                                        osd_message(&format!(
-                                            "Danmaku delay: {:.0} ms",
-                                            params.delay * 1000.
-                                        ));
+                            Some(action) => {
+                                if let Err(error) = menu::handle(action).await {
+                                    log_error(&error);
+                                }
+                            }
+                            None => log_error(&anyhow!(
+                                "command danmaku-menu: required argument action not set"
+                            )),
+                        }
+                    } else if arg1 == "danmaku-search" {
+                        let keyword = args.join(" ");
+                        if keyword.is_empty() {
+                            log_error(&anyhow!(
+                                "command danmaku-search: required argument keyword not set"
+                            ));
+                        } else {
+                            handle.abort();
+                            menu::close().await;
+                            handle = spawn(search(keyword, filter.clone()));
+                        }
+                    } else if arg1 == "danmaku-add-source" {
+                        match args.first() {
+                            Some(url) if is_http_link(url) => {
+                                handle.abort();
+                                menu::close().await;
+                                handle = spawn(add(url.clone(), filter.clone()));
+                            }
+                            Some(url) => log_error(&anyhow!(
+                                "command danmaku-add-source: invalid url {}",
+                                url
+                            )),
+                            None => log_error(&anyhow!(
+                                "command danmaku-add-source: required argument url not set"
+                            )),
+                        }
+                    } else if arg1 == "danmaku-sources" {
+                        handle.abort();
+                        menu::close().await;
+                        handle = spawn(sources(filter.clone()));
+                    } else if arg1 == "danmaku-refresh" {
+                        handle.abort();
+                        menu::close().await;
+                        handle = spawn(refresh(filter.clone()));
+                    } else if arg1 == "danmaku-export-ass" {
+                        let path = match args.first() {
+                            Some(path) => expand_path(path),
+                            None => get_property_string("path")
+                                .ok_or_else(|| anyhow!("command danmaku-export-ass: no file"))
+                                .and_then(|path| export::default_path(&path)),
+                        };
+                        match (path, &*COMMENTS.lock().await) {
+                            (Ok(path), Some(comments)) => {
+                                match export::save(
+                                    &path,
+                                    comments,
+                                    params.delay,
+                                    params.osd_width,
+                                    params.osd_height,
+                                    &options,
+                                ) {
+                                    Ok(()) => {
+                                        osd_message(&format!("Danmaku: exported to {}", path))
                                     }
-                                    None => {
-                                        log_error(&anyhow!("command danmaku-delay: invalid time"))
+                                    Err(error) => {
+                                        log_error(&error);
+                                        osd_message(&format!("Danmaku: {}", error));
                                     }
                                 }
                             }
+                            (Ok(_), None) => osd_message("Danmaku: no danmaku loaded"),
+                            (Err(error), _) => log_error(&error),
+                        }
+                    } else if arg1 == "danmaku-density" {
+                        match args.first() {
+                            Some(value) => match value.parse::<usize>().ok() {
+                                Some(value) => {
+                                    density = Some(value);
+                                    options.density = value;
+                                    options::set(options.clone());
+                                    if ENABLED.load(Ordering::SeqCst) {
+                                        if let Some(comments) = &mut *COMMENTS.lock().await {
+                                            reset_status(comments);
+                                            render(comments, params, &options);
+                                        }
+                                    }
+                                    osd_message(&if value == 0 {
+                                        "Danmaku density: unlimited".to_string()
+                                    } else {
+                                        format!("Danmaku density: {} per second", value)
+                                    });
+                                }
+                                None => {
+                                    log_error(&anyhow!("command danmaku-density: invalid number"))
+                                }
+                            },
+                            None => log_error(&anyhow!(
+                                "command danmaku-density: required argument density not set"
+                            )),
+                        }
+                    } else if arg1 == "danmaku-delay" {
+                        match args.first() {
+                            Some(seconds) => match seconds.parse::<f64>().ok() {
+                                Some(seconds) => {
+                                    params.delay += seconds;
+                                    if ENABLED.load(Ordering::SeqCst) {
+                                        if let Some(comments) = &mut *COMMENTS.lock().await {
+                                            reset_status(comments);
+                                            render(comments, params, &options);
+                                        }
+                                    }
+                                    osd_message(&format!(
+                                        "Danmaku delay: {:.0} ms",
+                                        params.delay * 1000.
+                                    ));
+                                }
+                                None => log_error(&anyhow!("command danmaku-delay: invalid time")),
+                            },
                             None => log_error(&anyhow!(
                                 "command danmaku-delay: required argument seconds not set"
                             )),
@@ -313,9 +432,9 @@ async fn main() -> c_int {
                     }
                 }
             }
-            mpv_event_id::MPV_EVENT_NONE => {
+            Event::Timeout => {
                 if let Some(comments) = &mut *COMMENTS.lock().await {
-                    render(comments, params, options);
+                    render(comments, params, &options);
                 }
             }
             _ => (),
@@ -323,120 +442,233 @@ async fn main() -> c_int {
     }
 }
 
-#[derive(Clone, Copy)]
-struct Row {
-    end: f64,
-    step: f64,
-}
-
-fn render(comments: &mut [Danmaku], params: Params, options: Options) {
-    let Some(pos) = get_property_f64(c"time-pos") else {
+fn render(comments: &mut Comments, params: Params, options: &Options) {
+    let Some(pos) = get_property_f64("time-pos") else {
         return;
     };
-    let mut width = 1920.;
-    let mut height = 1080.;
-    let ratio = params.osd_width / params.osd_height;
-    if width / height < ratio {
-        height = width / ratio;
-    } else if width / height > ratio {
-        width = height * ratio;
-    }
-    let spacing = options.font_size / 10.;
-    let mut rows = vec![
-        Row {
-            end: 0.,
-            step: MIN_STEP,
-        };
-        ((height * (1. - options.reserved_space) / (options.font_size + spacing))
-            as usize)
-            .max(1)
-    ];
+    let mut layout = Layout::new(params.osd_width, params.osd_height, options);
+    if options.avoid_subtitles {
+        block_subtitles(&mut layout, params);
+    }
+    let cursor = *comments.cursor.get_or_insert_with(|| {
+        // anything entered the screen earlier is gone by now, the widest comment is the
+        // slowest to leave
+        let max_width = comments
+            .danmaku
+            .iter()
+            .filter(|c| c.mode == Mode::Scroll && !c.blocked)
+            .map(|c| layout.comment_width(c, options))
+            .fold(0., f64::max);
+        let lookback = layout
+            .scroll_duration(max_width, options)
+            .max(FIXED_DURATION);
+        comments
+            .danmaku
+            .partition_point(|c| c.time + params.delay < pos - lookback)
+    });
+
+    if options.density > 0 {
+        let end = comments
+            .danmaku
+            .partition_point(|c| c.time + params.delay <= pos);
+        limit_density(&mut comments.danmaku, cursor..end, options.density);
+    }
 
     let mut danmaku = Vec::new();
     let mut rng = rng();
-    'it: for comment in comments.iter_mut().filter(|c| !c.blocked) {
+    for comment in comments.danmaku[cursor..].iter_mut().filter(|c| !c.blocked) {
         let time = comment.time + params.delay;
         if time > pos {
             break;
         }
 
-        let status = match &mut comment.status {
+        let span = layout.span(comment, options);
+        if comment.mode != Mode::Scroll {
+            let end = time + FIXED_DURATION;
+            if end <= pos {
+                continue;
+            }
+            let row = match &comment.status {
+                Status::Status(status) => status.row,
+                Status::Overlapping => continue,
+                Status::Uninitialized => {
+                    let Some(row) = layout.fixed_row(comment.mode, time, span, !options.no_overlap)
+                    else {
+                        comment.status = Status::Overlapping;
+                        continue;
+                    };
+                    comment.status.insert(StatusInner { row, step: 0. });
+                    row
+                }
+            };
+            layout.occupy_fixed_row(comment.mode, row, span, end);
+            // placed before the subtitle showed up, hidden until it is gone
+            if layout.is_blocked(comment.mode, row, span) {
+                continue;
+            }
+            danmaku.push(format!(
+                "{{\\an8\\pos({},{}){}}}{}",
+                layout.width / 2.,
+                layout.y(comment.mode, row, span),
+                style(comment, options),
+                comment.text()
+            ));
+            continue;
+        }
+
+        let comment_width = layout.comment_width(comment, options);
+        let elapsed = pos - time;
+        let status = match &comment.status {
             Status::Status(status) => status,
             Status::Overlapping => continue,
-            Status::Uninitialized => 'status: {
-                let ticks = (pos - time) / INTERVAL;
-                for (row, status) in rows.iter().enumerate() {
-                    if status.end < width - width * ticks * MIN_STEP {
-                        let max_step = if status.end == 0. {
-                            MAX_STEP
-                        } else {
-                            // 1 / max_step - ticks = status.end / width / status.step
-                            let max_step = 1. / (ticks + status.end / width / status.step);
-                            max_step.min(MAX_STEP)
-                        };
-                        let step = rng.random_range(MIN_STEP..max_step);
-                        let x = width - width * ticks * step;
-                        break 'status comment.status.insert(StatusInner { x, row, step });
+            Status::Uninitialized => {
+                let (row, step) = match layout.scroll_row(ticks(elapsed, options), span, &mut rng) {
+                    Some(row) => row,
+                    None if options.no_overlap => {
+                        comment.status = Status::Overlapping;
+                        continue;
                     }
-                }
-                if options.no_overlap {
-                    comment.status = Status::Overlapping;
-                    continue 'it;
-                }
-                let row = rows
-                    .iter()
-                    .enumerate()
-                    .min_by(|a, b| a.1.end.partial_cmp(&b.1.end).unwrap())
-                    .map(|(row, _)| row)
-                    .unwrap();
-                let step = MIN_STEP;
-                let x = width - width * ticks * step;
-                comment.status.insert(StatusInner { x, row, step })
+                    None => (layout.earliest_scroll_row(span), MIN_STEP),
+                };
+                comment.status.insert(StatusInner { row, step })
             }
         };
-        if status.x + comment.count as f64 * options.font_size + spacing <= 0. {
+        // derived from the playback position, so that stalls and speed changes do not drift
+        let x = layout.scroll_x(status.step, elapsed, options);
+        if x + comment_width <= 0. {
             continue;
         }
+        let row = status.row;
+        layout.occupy_scroll_row(row, span, x + comment_width, status.step);
+        if layout.is_blocked(Mode::Scroll, row, span) {
+            continue;
+        }
+
         danmaku.push(format!(
-            "{{\\pos({},{})\\c&H{:x}{:x}{:x}&\\alpha&H{:x}\\fs{}\\bord1.5\\shad0\\b1\\q2}}{}",
-            status.x,
-            status.row as f64 * (options.font_size + spacing),
-            comment.b,
-            comment.g,
-            comment.r,
-            options.transparency,
-            options.font_size,
-            comment.message
+            "{{\\pos({},{}){}}}{}",
+            x,
+            layout.y(Mode::Scroll, row, span),
+            style(comment, options),
+            comment.text()
         ));
+    }
+    osd_overlay(
+        0,
+        &danmaku.join("\n"),
+        layout.width as i64,
+        layout.height as i64,
+    );
 
-        status.x -= width * status.step * params.speed * options.speed;
-        if let Some(row) = rows.get_mut(status.row) {
-            let end = status.x + comment.count as f64 * options.font_size + spacing;
-            if end / status.step > row.end / row.step {
-                *row = Row {
-                    end,
-                    step: status.step,
-                };
+    let gone = comments.danmaku[cursor..]
+        .iter()
+        .take_while(|c| has_left(c, pos, params, &layout, options))
+        .count();
+    comments.cursor = Some(cursor + gone);
+}
+
+fn lines(text: &str) -> usize {
+    text.lines().filter(|line| !line.trim().is_empty()).count()
+}
+
+/// keep new comments away from the subtitles shown, estimated from the subtitle font size,
+/// margin and position scaled from the 720p reference height
+fn block_subtitles(layout: &mut Layout, params: Params) {
+    let sub_lines = if params.sub_visible {
+        params.sub_lines
+    } else {
+        0
+    };
+    let secondary_sub_lines = if params.secondary_sub_visible {
+        params.secondary_sub_lines
+    } else {
+        0
+    };
+    if sub_lines == 0 && secondary_sub_lines == 0 {
+        return;
+    }
+
+    let scale = layout.height / 720.;
+    let line_height = params.sub_font_size * scale;
+    let margin = params.sub_margin_y * scale;
+    if sub_lines > 0 {
+        let bottom = layout.height * params.sub_pos / 100. - margin;
+        layout.block(bottom - sub_lines as f64 * line_height, bottom);
+    }
+    // secondary subtitles are shown at the top
+    if secondary_sub_lines > 0 {
+        layout.block(margin, margin + secondary_sub_lines as f64 * line_height);
+    }
+}
+
+/// hide the comments entering the screen in `range` which do not rank within the `density`
+/// highest priorities of the second they are sent in, comments already shown keep their place
+/// and those dropped for overlapping leave theirs to the others
+fn limit_density(comments: &mut [Danmaku], range: Range<usize>, density: usize) {
+    let mut index = range.start;
+    while index < range.end {
+        let second = comments[index].time.floor();
+        let end = comments.partition_point(|c| c.time < second + 1.);
+        let start = comments.partition_point(|c| c.time < second);
+        let mut placed = 0;
+        let mut waiting = Vec::new();
+        for (other, comment) in comments[start..end].iter().enumerate() {
+            match comment.status {
+                _ if comment.blocked => (),
+                Status::Status(_) => placed += 1,
+                Status::Overlapping => (),
+                Status::Uninitialized => waiting.push(start + other),
+            }
+        }
+        waiting.sort_by(|&a, &b| {
+            priority(&comments[b])
+                .total_cmp(&priority(&comments[a]))
+                .then(a.cmp(&b))
+        });
+        for &other in waiting.iter().skip(density.saturating_sub(placed)) {
+            if range.contains(&other) {
+                comments[other].status = Status::Overlapping;
             }
         }
+        index = end.max(index + 1);
+    }
+}
+
+/// whether the comment is not shown again until the next seek
+fn has_left(
+    comment: &Danmaku,
+    pos: f64,
+    params: Params,
+    layout: &Layout,
+    options: &Options,
+) -> bool {
+    let time = comment.time + params.delay;
+    if time > pos {
+        return false;
+    }
+    if comment.blocked {
+        return true;
+    }
+    if comment.mode != Mode::Scroll {
+        return time + FIXED_DURATION <= pos;
+    }
+    match &comment.status {
+        Status::Status(status) => {
+            layout.scroll_x(status.step, pos - time, options)
+                + layout.comment_width(comment, options)
+                <= 0.
+        }
+        Status::Overlapping => true,
+        Status::Uninitialized => false,
     }
-    osd_overlay(&danmaku.join("\n"), width as i64, height as i64);
 }
 
 async fn get(filter: Arc<Filter>) {
-    let Some(path) = get_property_string(c"path") else {
+    let Some(path) = get_property_string("path") else {
         return;
     };
 
     match get_danmaku(&path, filter).await {
-        Ok(danmaku) => {
-            let n = danmaku.iter().filter(|c| !c.blocked).count();
-            *COMMENTS.lock().await = Some(danmaku);
-            if ENABLED.load(Ordering::SeqCst) {
-                unsafe { mpv_wakeup(CTX) };
-                loaded(n);
-            }
-        }
+        Ok(danmaku) => store(danmaku).await,
         Err(error) => {
             log_error(&error);
             if ENABLED.load(Ordering::SeqCst) {
@@ -446,10 +678,80 @@ async fn get(filter: Arc<Filter>) {
     }
 }
 
-fn reset_status(comments: &mut [Danmaku]) {
-    for comment in comments {
+async fn search(keyword: String, filter: Arc<Filter>) {
+    let Some(path) = get_property_string("path") else {
+        return;
+    };
+
+    osd_message(&format!("Danmaku: searching for {}", keyword));
+    match search_danmaku(&path, &keyword, filter).await {
+        Ok(danmaku) => store(danmaku).await,
+        Err(error) => {
+            log_error(&error);
+            osd_message(&format!("Danmaku: {}", error));
+        }
+    }
+}
+
+async fn add(url: String, filter: Arc<Filter>) {
+    let Some(path) = get_property_string("path") else {
+        return;
+    };
+
+    osd_message(&format!("Danmaku: adding {}", url));
+    match add_source(&path, &url, filter).await {
+        Ok(danmaku) => store(danmaku).await,
+        Err(error) => {
+            log_error(&error);
+            osd_message(&format!("Danmaku: {}", error));
+        }
+    }
+}
+
+async fn sources(filter: Arc<Filter>) {
+    let Some(path) = get_property_string("path") else {
+        return;
+    };
+
+    match toggle_source(&path, filter).await {
+        Ok(danmaku) => store(danmaku).await,
+        Err(error) => {
+            log_error(&error);
+            osd_message(&format!("Danmaku: {}", error));
+        }
+    }
+}
+
+async fn refresh(filter: Arc<Filter>) {
+    let Some(path) = get_property_string("path") else {
+        return;
+    };
+
+    osd_message("Danmaku: refreshing");
+    match refresh_danmaku(&path, filter).await {
+        Ok(danmaku) => store(danmaku).await,
+        Err(error) => {
+            log_error(&error);
+            osd_message(&format!("Danmaku: {}", error));
+        }
+    }
+}
+
+async fn store(danmaku: Vec<Danmaku>) {
+    let n = danmaku.iter().filter(|c| !c.blocked).count();
+    *COMMENTS.lock().await = Some(Comments::new(danmaku));
+    if ENABLED.load(Ordering::SeqCst) {
+        host().wakeup();
+        loaded(n);
+    }
+}
+
+/// lay out the comments again from the current position
+fn reset_status(comments: &mut Comments) {
+    for comment in comments.iter_mut() {
         comment.status = Status::Uninitialized;
     }
+    comments.cursor = None;
 }
 
 fn loaded(n: usize) {
@@ -459,3 +761,365 @@ fn loaded(n: usize) {
         if n > 1 { "s" } else { "" }
     ));
 }
+
+fn report_errors(errors: &[anyhow::Error]) {
+    for error in errors {
+        log_error(error);
+    }
+    if !errors.is_empty() {
+        osd_message(&format!(
+            "Danmaku: {}",
+            errors
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join("\n")
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        dandanplay::Source,
+        host::fake::{FakeHost, LOCK},
+        layout::MAX_DURATION,
+    };
+
+    fn comment(message: &str, time: f64, mode: Mode) -> Danmaku {
+        Danmaku::new(message, time, mode, 0xffffff, Source::Dandan, "1")
+    }
+
+    /// start playing `comments` with a 1920x1080 osd, then replay the queued events
+    fn play(comments: Vec<Danmaku>, events: impl FnOnce(&FakeHost)) -> Arc<FakeHost> {
+        let _lock = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        CLIENT_NAME.get_or_init(|| "danmaku".to_string());
+        ENABLED.store(false, Ordering::SeqCst);
+        *COMMENTS.blocking_lock() = Some(Comments::new(comments));
+
+        let fake = Arc::new(FakeHost::default());
+        for property in [
+            Property::OsdWidth(1920.),
+            Property::OsdHeight(1080.),
+            Property::DisplayFps(60.),
+            Property::Pause(false),
+        ] {
+            fake.push(Event::PropertyChange(property));
+        }
+        events(&fake);
+        set_host(fake.clone());
+
+        let code = Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(run(Options::default(), Arc::default()));
+        assert_eq!(code, 0);
+        fake
+    }
+
+    fn message(args: &[&str]) -> Event {
+        Event::ClientMessage(args.iter().map(ToString::to_string).collect())
+    }
+
+    /// x coordinate of the scrolling comment showing `text`
+    fn scroll_x(frame: &str, text: &str) -> f64 {
+        let line = frame
+            .lines()
+            .find(|line| line.ends_with(&format!("}}{}", text)))
+            .unwrap();
+        line["{\\pos(".len()..]
+            .split(',')
+            .next()
+            .unwrap()
+            .parse()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_render() {
+        let fake = play(
+            vec![
+                comment("scroll", 1., Mode::Scroll),
+                comment("top", 2., Mode::Top),
+            ],
+            |fake| {
+                fake.push_property("time-pos", 1.5);
+                fake.push(message(&["toggle-danmaku"]));
+                fake.push(Event::Timeout);
+                fake.push_property("time-pos", 2);
+                fake.push(Event::Timeout);
+                // a stall skipping several redraws
+                fake.push_property("time-pos", 3);
+                fake.push(Event::Timeout);
+                // seek back before the first comment
+                fake.push_property("time-pos", 0.5);
+                fake.push(Event::PlaybackRestart);
+            },
+        );
+
+        let frames = fake.frames(0);
+        assert_eq!(frames.len(), 5);
+        // entered the screen half a second ago
+        let x = scroll_x(&frames[0], "scroll");
+        assert!(x > 1920. - 1920. * 0.5 / 12. * 1.3 && x <= 1920. - 1920. * 0.5 / 12.);
+        assert!(!frames[0].contains("}top"));
+        // positions follow the playback time, not the number of redraws
+        assert_eq!(scroll_x(&frames[1], "scroll"), x);
+        let distance = x - scroll_x(&frames[2], "scroll");
+        assert!(distance > 0.);
+        let stalled = scroll_x(&frames[2], "scroll") - scroll_x(&frames[3], "scroll");
+        assert!((stalled - distance * 2.).abs() < 1e-6);
+        assert!(frames[3].contains("{\\an8\\pos(960,0)"));
+        assert!(frames[3].ends_with("}top"));
+        assert!(frames[4].is_empty());
+        assert_eq!(
+            fake.messages.lock().unwrap()[0],
+            "Loaded 2 danmaku comments"
+        );
+    }
+
+    #[test]
+    fn test_wide_comment() {
+        let wide = "w".repeat(100);
+        let fake = play(vec![comment(&wide, 1., Mode::Scroll)], |fake| {
+            // seek to where a narrow comment would have left the screen
+            fake.push_property("time-pos", 13.5);
+            fake.push(message(&["toggle-danmaku"]));
+        });
+
+        let frames = fake.frames(0);
+        assert!(frames[0].ends_with(&wide));
+        assert!(scroll_x(&frames[0], &wide) < 0.);
+    }
+
+    #[test]
+    fn test_messages() {
+        let fake = play(vec![comment("scroll", 1., Mode::Scroll)], |fake| {
+            fake.push_property("time-pos", 1.5);
+            fake.push(message(&["toggle-danmaku"]));
+            fake.push(message(&["danmaku-delay", "2"]));
+            fake.push(message(&["danmaku-delay", "x"]));
+            fake.push(message(&["toggle-danmaku"]));
+        });
+
+        let frames = fake.frames(0);
+        assert_eq!(frames.len(), 3);
+        assert!(frames[0].ends_with("}scroll"));
+        assert!(frames[1].is_empty());
+        assert!(frames[2].is_empty());
+        assert_eq!(
+            fake.messages.lock().unwrap()[1..],
+            ["Danmaku delay: 2000 ms", "Danmaku: off"]
+        );
+    }
+
+    #[test]
+    fn test_script_opts() {
+        let fake = play(
+            vec![
+                comment("spoiler", 1., Mode::Top),
+                comment("hello", 1., Mode::Top),
+            ],
+            |fake| {
+                fake.push_property("time-pos", 1.5);
+                fake.push(message(&["toggle-danmaku"]));
+                fake.push(Event::PropertyChange(Property::ScriptOpts(vec![
+                    ("danmaku-filter".to_string(), "spoil".to_string()),
+                    ("other-filter".to_string(), "hello".to_string()),
+                ])));
+                fake.push(Event::PropertyChange(Property::ScriptOpts(vec![(
+                    "danmaku-font_size".to_string(),
+                    "big".to_string(),
+                )])));
+            },
+        );
+
+        let frames = fake.frames(0);
+        assert_eq!(frames.len(), 3);
+        assert_eq!(frames[0].lines().count(), 2);
+        assert!(frames[1].ends_with("}hello"));
+        assert!(!frames[1].contains("spoiler"));
+        // the filter from the previous value no longer applies
+        assert_eq!(frames[2].lines().count(), 2);
+        let messages = fake.messages.lock().unwrap();
+        assert_eq!(messages[1], "Danmaku: options updated");
+        assert_eq!(messages[2], "Danmaku: option font_size: invalid value big");
+    }
+
+    #[test]
+    fn test_density() {
+        let fake = play(
+            vec![
+                comment("plain", 1., Mode::Scroll),
+                Danmaku::new("red", 1.2, Mode::Scroll, 0xff0000, Source::Dandan, "2"),
+                comment("next", 1.5, Mode::Scroll),
+                comment("later", 2.5, Mode::Scroll),
+            ],
+            |fake| {
+                fake.push_property("time-pos", 3);
+                fake.push(message(&["danmaku-density", "1"]));
+                fake.push(message(&["toggle-danmaku"]));
+                // kept when the options are reloaded
+                fake.push(Event::PropertyChange(Property::ScriptOpts(vec![(
+                    "danmaku-speed".to_string(),
+                    "1".to_string(),
+                )])));
+                fake.push(message(&["danmaku-density", "0"]));
+            },
+        );
+
+        let frames = fake.frames(0);
+        let shown = |frame: &str| {
+            frame
+                .lines()
+                .map(|line| line.rsplit('}').next().unwrap().to_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(shown(&frames[0]), ["red", "later"]);
+        assert_eq!(shown(&frames[1]), ["red", "later"]);
+        assert_eq!(shown(&frames[2]), ["plain", "red", "next", "later"]);
+        assert_eq!(
+            fake.messages.lock().unwrap()[0],
+            "Danmaku density: 1 per second"
+        );
+    }
+
+    #[test]
+    fn test_limit_density() {
+        let red = |message, time| {
+            Danmaku::new(message, time, Mode::Scroll, 0xff0000, Source::Dandan, "1")
+        };
+        let mut comments = vec![
+            red("dropped", 1.),
+            comment("plain", 1.2, Mode::Scroll),
+            comment("shown", 1.4, Mode::Scroll),
+            red("pending", 1.6),
+            comment("next", 1.8, Mode::Scroll),
+            comment("later", 2.5, Mode::Scroll),
+        ];
+        comments[0].status = Status::Overlapping;
+        comments[2].status.insert(StatusInner { row: 0, step: 0. });
+
+        limit_density(&mut comments, 1..6, 2);
+        let placed = comments
+            .iter()
+            .filter(|c| !matches!(c.status, Status::Overlapping))
+            .map(|c| c.message.as_str())
+            .collect::<Vec<_>>();
+        // the shown comment and the red one left take the two places of the first second
+        assert_eq!(placed, ["shown", "pending", "later"]);
+    }
+
+    #[test]
+    fn test_subtitles() {
+        let fake = play(
+            (0..30)
+                .map(|i| comment(&i.to_string(), 1., Mode::Scroll))
+                .collect(),
+            |fake| {
+                fake.push_property("time-pos", 1);
+                fake.push(message(&["toggle-danmaku"]));
+                fake.push(Event::PropertyChange(Property::SubText(
+                    "first\nsecond".to_string(),
+                )));
+                fake.push(Event::PropertyChange(Property::SubVisibility(true)));
+                fake.push(Event::PlaybackRestart);
+                fake.push(Event::PropertyChange(Property::SubVisibility(false)));
+                fake.push(Event::PlaybackRestart);
+                fake.push(Event::PropertyChange(Property::SubVisibility(true)));
+                fake.push(Event::PropertyChange(Property::SubPos(50.)));
+                fake.push(Event::PlaybackRestart);
+            },
+        );
+
+        let frames = fake.frames(0);
+        // 24 rows of 44 pixels, the two subtitle lines cover 882 to 1047
+        assert_eq!(frames[0].lines().count(), 24);
+        assert_eq!(frames[1].lines().count(), 20);
+        assert!(frames[1].lines().all(|line| !line.contains(",880)")));
+        assert_eq!(frames[2].lines().count(), 24);
+        // moved up to cover 342 to 507
+        assert_eq!(frames[3].lines().count(), 19);
+        assert!(frames[3].lines().all(|line| !line.contains(",352)")));
+    }
+
+    #[test]
+    fn test_subtitles_after_placed() {
+        let mut comments = (0..30)
+            .map(|i| comment(&i.to_string(), 1., Mode::Scroll))
+            .collect::<Vec<_>>();
+        comments.push(comment("bottom", 1., Mode::Bottom));
+        let fake = play(comments, |fake| {
+            fake.push_property("time-pos", 1);
+            fake.push(message(&["toggle-danmaku"]));
+            fake.push(Event::PropertyChange(Property::SubText(
+                "first\nsecond".to_string(),
+            )));
+            fake.push(Event::PropertyChange(Property::SubVisibility(true)));
+            fake.push(Event::Timeout);
+            fake.push(Event::PropertyChange(Property::SubVisibility(false)));
+            fake.push(Event::Timeout);
+        });
+
+        let frames = fake.frames(0);
+        assert_eq!(frames[0].lines().count(), 25);
+        assert!(frames[0].ends_with("}bottom"));
+        // the comments placed where the subtitles show up are hidden, not moved
+        assert_eq!(frames[1].lines().count(), 20);
+        assert!(frames[1].lines().all(|line| !line.contains(",880)")));
+        assert!(!frames[1].contains("bottom"));
+        assert_eq!(frames[2], frames[0]);
+    }
+
+    /// render a two hour timeline with one comment every 0.144 seconds near its end
+    fn render_late(frames: usize, seek_every_frame: bool) -> (Comments, std::time::Duration) {
+        let _lock = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let fake = Arc::new(FakeHost::default());
+        set_host(fake.clone());
+
+        let mut comments = Comments::new(
+            (0..50_000)
+                .map(|i| comment(&i.to_string(), i as f64 * 0.144, Mode::Scroll))
+                .collect(),
+        );
+        let params = Params {
+            osd_width: 1920.,
+            osd_height: 1080.,
+            ..Default::default()
+        };
+        let start = std::time::Instant::now();
+        for frame in 0..frames {
+            fake.set_property("time-pos", 7000. + frame as f64 / 60.);
+            if seek_every_frame {
+                comments.cursor = Some(0);
+            }
+            render(&mut comments, params, &Options::default());
+        }
+        (comments, start.elapsed())
+    }
+
+    #[test]
+    fn test_cursor() {
+        let (comments, _) = render_late(60, false);
+        let cursor = comments.cursor.unwrap();
+        // comments which left the screen are skipped, including their width
+        assert!(comments[cursor].time > 7001. - MAX_DURATION * 1.5);
+        assert!(comments[cursor].time <= 7001.);
+    }
+
+    /// `cargo test --release -- --ignored --nocapture bench_render`
+    #[test]
+    #[ignore]
+    fn bench_render() {
+        let (_, cursor) = render_late(600, false);
+        let (_, scan) = render_late(600, true);
+        println!(
+            "per frame: {:?} with cursor, {:?} scanning",
+            cursor / 600,
+            scan / 600
+        );
+        assert!(cursor < scan);
+    }
+}
@@ -23,11 +23,7 @@ pub fn log_error(error: &Error) {
 //     use std::path::PathBuf;
 //     use tokio::io::AsyncWriteExt;
 
-//     let (options, _) = crate::options::read_options()
-//         .map_err(|e| crate::log::log_error(&e))
-//         .ok()
-//         .flatten()
-//         .unwrap_or_default();
+//     let options = crate::options::get();
 
 //     if !["true", "on", "enable"].contains(&options.log) {
 //         return Ok(());
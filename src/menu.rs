@@ -0,0 +1,155 @@
+use crate::{
+    CLIENT_NAME,
+    mpv::{command, osd_overlay, remove_overlay},
+};
+use anyhow::{Result, anyhow};
+use std::sync::LazyLock;
+use tokio::sync::{Mutex, oneshot};
+
+const OVERLAY_ID: i64 = 1;
+const SECTION: &str = "danmaku-menu";
+const VISIBLE_ITEMS: usize = 10;
+
+struct Menu {
+    title: String,
+    items: Vec<String>,
+    selected: usize,
+    sender: oneshot::Sender<usize>,
+}
+
+static MENU: LazyLock<Mutex<Option<Menu>>> = LazyLock::new(|| Mutex::new(None));
+
+impl Menu {
+    fn show(&self) {
+        let first = self
+            .selected
+            .saturating_sub(VISIBLE_ITEMS / 2)
+            .min(self.items.len().saturating_sub(VISIBLE_ITEMS));
+        let mut lines = vec![format!("{{\\b1}}{}", escape(&self.title))];
+        if first > 0 {
+            lines.push("...".to_string());
+        }
+        for (index, item) in self
+            .items
+            .iter()
+            .enumerate()
+            .skip(first)
+            .take(VISIBLE_ITEMS)
+        {
+            lines.push(if index == self.selected {
+                format!("{{\\c&H00FFFF&}}> {}{{\\c}}", escape(item))
+            } else {
+                format!("   {}", escape(item))
+            });
+        }
+        if first + VISIBLE_ITEMS < self.items.len() {
+            lines.push("...".to_string());
+        }
+        osd_overlay(
+            OVERLAY_ID,
+            &format!(
+                "{{\\an7\\pos(20,20)\\fs24\\bord1.5\\q2}}{}",
+                lines.join("\\N")
+            ),
+            0,
+            720,
+        );
+    }
+}
+
+/// show a menu and wait for the user to pick an item, returns the index of the chosen item
+pub async fn select(title: &str, items: Vec<String>) -> Result<usize> {
+    if items.is_empty() {
+        return Err(anyhow!("nothing to select"));
+    }
+
+    let (sender, receiver) = oneshot::channel();
+    let menu = Menu {
+        title: format!(
+            "{} (UP/DOWN to move, ENTER to confirm, ESC to cancel)",
+            title
+        ),
+        items,
+        selected: 0,
+        sender,
+    };
+    menu.show();
+    if MENU.lock().await.replace(menu).is_none() {
+        bind_keys();
+    }
+
+    receiver.await.map_err(|_| anyhow!("selection cancelled"))
+}
+
+/// handle `danmaku-menu <up|down|select|cancel>` script messages
+pub async fn handle(action: &str) -> Result<()> {
+    let mut menu = MENU.lock().await;
+    let Some(inner) = menu.as_mut() else {
+        return Ok(());
+    };
+    match action {
+        "up" => {
+            inner.selected = inner
+                .selected
+                .checked_sub(1)
+                .unwrap_or(inner.items.len() - 1);
+            inner.show();
+        }
+        "down" => {
+            inner.selected = (inner.selected + 1) % inner.items.len();
+            inner.show();
+        }
+        "select" => {
+            let inner = menu.take().unwrap();
+            hide();
+            _ = inner.sender.send(inner.selected);
+        }
+        "cancel" => {
+            menu.take();
+            hide();
+        }
+        _ => return Err(anyhow!("command danmaku-menu: unknown action {}", action)),
+    }
+    Ok(())
+}
+
+/// dismiss the menu if one is shown, the pending selection fails
+pub async fn close() {
+    if MENU.lock().await.take().is_some() {
+        hide();
+    }
+}
+
+fn bind_keys() {
+    let client = CLIENT_NAME.get().map(String::as_str).unwrap_or_default();
+    let bindings = [
+        ("UP", "up"),
+        ("WHEEL_UP", "up"),
+        ("DOWN", "down"),
+        ("WHEEL_DOWN", "down"),
+        ("ENTER", "select"),
+        ("KP_ENTER", "select"),
+        ("ESC", "cancel"),
+    ]
+    .map(|(key, action)| {
+        format!(
+            "{} script-message-to {} {} {}",
+            key, client, SECTION, action
+        )
+    })
+    .join("\n");
+    command(&["define-section", SECTION, &bindings, "force"]);
+    command(&["enable-section", SECTION]);
+}
+
+fn hide() {
+    command(&["disable-section", SECTION]);
+    remove_overlay(OVERLAY_ID);
+}
+
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\u{feff}")
+        .replace('{', "\\{")
+        .replace('}', "\\}")
+        .replace('\n', " ")
+}
@@ -4,193 +4,344 @@
 use crate::{
     CTX,
     ffi::{
-        mpv_command, mpv_command_node, mpv_command_ret, mpv_error_string, mpv_format, mpv_free,
-        mpv_free_node_contents, mpv_get_property, mpv_node, mpv_node_list, u,
+        mpv_command, mpv_command_node, mpv_command_ret, mpv_error_string, mpv_event_client_message,
+        mpv_event_id, mpv_event_property, mpv_format, mpv_free, mpv_free_node_contents,
+        mpv_get_property, mpv_node, mpv_node_list, mpv_wait_event, mpv_wakeup, u,
     },
-    log_code,
+    host::{Event, Host, Property, host},
+    log::{log_code, log_error},
 };
 use anyhow::{Result, anyhow};
 use std::{
-    ffi::{CStr, CString, c_char},
+    ffi::{CStr, CString, c_char, c_int},
     mem::MaybeUninit,
     ptr::{addr_of_mut, null, null_mut},
+    slice::from_raw_parts,
 };
 
-pub fn osd_overlay(data: &str, width: i64, height: i64) {
-    let mut keys = [c"name", c"id", c"format", c"data", c"res_x", c"res_y"]
-        .map(|key| CString::from(key).into_raw());
-    let value1 = CString::from(c"osd-overlay").into_raw();
-    let value3 = CString::from(c"ass-events").into_raw();
-    let value4 = CString::new(data).unwrap().into_raw();
-    let mut values = [
-        mpv_node {
-            format: mpv_format::MPV_FORMAT_STRING,
-            u: u { string: value1 },
-        },
-        mpv_node {
-            format: mpv_format::MPV_FORMAT_INT64,
-            u: u { int64: 0 },
-        },
-        mpv_node {
-            format: mpv_format::MPV_FORMAT_STRING,
-            u: u { string: value3 },
-        },
-        mpv_node {
-            format: mpv_format::MPV_FORMAT_STRING,
-            u: u { string: value4 },
-        },
-        mpv_node {
-            format: mpv_format::MPV_FORMAT_INT64,
-            u: u { int64: width },
-        },
-        mpv_node {
-            format: mpv_format::MPV_FORMAT_INT64,
-            u: u { int64: height },
-        },
-    ];
-    assert_eq!(keys.len(), values.len());
-
-    let mut list = mpv_node_list {
-        num: keys.len().try_into().unwrap(),
-        values: values.as_mut_ptr(),
-        keys: keys.as_mut_ptr(),
-    };
-    let mut args = mpv_node {
-        format: mpv_format::MPV_FORMAT_NODE_MAP,
-        u: u {
-            list: addr_of_mut!(list),
-        },
-    };
-    let error = unsafe { mpv_command_node(CTX, addr_of_mut!(args), null_mut()) };
-    if error < 0 {
-        log_code(error);
+/// the mpv client the plugin is loaded into
+pub struct Mpv;
+
+impl Host for Mpv {
+    fn wait_event(&self, timeout: f64) -> Event {
+        let event = unsafe { &*mpv_wait_event(CTX, timeout) };
+        match event.event_id {
+            mpv_event_id::MPV_EVENT_NONE => Event::Timeout,
+            mpv_event_id::MPV_EVENT_SHUTDOWN => Event::Shutdown,
+            mpv_event_id::MPV_EVENT_FILE_LOADED => Event::FileLoaded,
+            mpv_event_id::MPV_EVENT_PLAYBACK_RESTART => Event::PlaybackRestart,
+            mpv_event_id::MPV_EVENT_PROPERTY_CHANGE => {
+                let data = unsafe { &*(event.data as *mut mpv_event_property) };
+                let name = unsafe { CStr::from_ptr(data.name) }.to_bytes();
+                if data.format == mpv_format::MPV_FORMAT_NONE {
+                    // no subtitle is loaded
+                    return match name {
+                        b"sub-text" => Event::PropertyChange(Property::SubText(String::new())),
+                        b"secondary-sub-text" => {
+                            Event::PropertyChange(Property::SecondarySubText(String::new()))
+                        }
+                        _ => Event::Other,
+                    };
+                }
+                let string = || {
+                    unsafe { CStr::from_ptr(*(data.data as *mut *const c_char)) }
+                        .to_string_lossy()
+                        .into_owned()
+                };
+                let flag = || unsafe { *(data.data as *mut c_int) } != 0;
+                let property = match name {
+                    b"pause" => Property::Pause(flag()),
+                    b"display-fps" => Property::DisplayFps(unsafe { *(data.data as *mut f64) }),
+                    b"osd-width" => Property::OsdWidth(unsafe { *(data.data as *mut f64) }),
+                    b"osd-height" => Property::OsdHeight(unsafe { *(data.data as *mut f64) }),
+                    b"sub-text" => Property::SubText(string()),
+                    b"sub-visibility" => Property::SubVisibility(flag()),
+                    b"secondary-sub-text" => Property::SecondarySubText(string()),
+                    b"secondary-sub-visibility" => Property::SecondarySubVisibility(flag()),
+                    b"sub-font-size" => Property::SubFontSize(unsafe { *(data.data as *mut f64) }),
+                    b"sub-margin-y" => Property::SubMarginY(unsafe { *(data.data as *mut f64) }),
+                    b"sub-pos" => Property::SubPos(unsafe { *(data.data as *mut f64) }),
+                    b"script-opts" => {
+                        Property::ScriptOpts(node_map(unsafe { &*(data.data as *mut mpv_node) }))
+                    }
+                    _ => return Event::Other,
+                };
+                Event::PropertyChange(property)
+            }
+            mpv_event_id::MPV_EVENT_CLIENT_MESSAGE => {
+                let data = unsafe { &*(event.data as *mut mpv_event_client_message) };
+                if data.args.is_null() {
+                    return Event::Other;
+                }
+                let args = unsafe { from_raw_parts(data.args, data.num_args.try_into().unwrap()) };
+                Event::ClientMessage(
+                    args.iter()
+                        .map(|&arg| {
+                            unsafe { CStr::from_ptr(arg) }
+                                .to_string_lossy()
+                                .into_owned()
+                        })
+                        .collect(),
+                )
+            }
+            _ => Event::Other,
+        }
     }
 
-    unsafe {
-        _ = keys.map(|key| CString::from_raw(key));
-        _ = CString::from_raw(value1);
-        _ = CString::from_raw(value3);
-        _ = CString::from_raw(value4);
+    fn wakeup(&self) {
+        unsafe { mpv_wakeup(CTX) };
     }
-}
 
-pub fn remove_overlay() {
-    let mut keys = [c"name", c"id", c"format", c"data"].map(|key| CString::from(key).into_raw());
-    let value1 = CString::from(c"osd-overlay").into_raw();
-    let value3 = CString::from(c"none").into_raw();
-    let value4 = CString::from(c"").into_raw();
-    let mut values = [
-        mpv_node {
-            format: mpv_format::MPV_FORMAT_STRING,
-            u: u { string: value1 },
-        },
-        mpv_node {
-            format: mpv_format::MPV_FORMAT_INT64,
-            u: u { int64: 0 },
-        },
-        mpv_node {
-            format: mpv_format::MPV_FORMAT_STRING,
-            u: u { string: value3 },
-        },
-        mpv_node {
-            format: mpv_format::MPV_FORMAT_STRING,
-            u: u { string: value4 },
-        },
-    ];
-    assert_eq!(keys.len(), values.len());
-
-    let mut list = mpv_node_list {
-        num: keys.len().try_into().unwrap(),
-        values: values.as_mut_ptr(),
-        keys: keys.as_mut_ptr(),
-    };
-    let mut args = mpv_node {
-        format: mpv_format::MPV_FORMAT_NODE_MAP,
-        u: u {
-            list: addr_of_mut!(list),
-        },
-    };
-    let error = unsafe { mpv_command_node(CTX, addr_of_mut!(args), null_mut()) };
-    if error < 0 {
-        log_code(error);
+    fn osd_overlay(&self, id: i64, data: &str, width: i64, height: i64) {
+        let mut keys = [c"name", c"id", c"format", c"data", c"res_x", c"res_y"]
+            .map(|key| CString::from(key).into_raw());
+        let value1 = CString::from(c"osd-overlay").into_raw();
+        let value3 = CString::from(c"ass-events").into_raw();
+        let value4 = CString::new(data).unwrap().into_raw();
+        let mut values = [
+            mpv_node {
+                format: mpv_format::MPV_FORMAT_STRING,
+                u: u { string: value1 },
+            },
+            mpv_node {
+                format: mpv_format::MPV_FORMAT_INT64,
+                u: u { int64: id },
+            },
+            mpv_node {
+                format: mpv_format::MPV_FORMAT_STRING,
+                u: u { string: value3 },
+            },
+            mpv_node {
+                format: mpv_format::MPV_FORMAT_STRING,
+                u: u { string: value4 },
+            },
+            mpv_node {
+                format: mpv_format::MPV_FORMAT_INT64,
+                u: u { int64: width },
+            },
+            mpv_node {
+                format: mpv_format::MPV_FORMAT_INT64,
+                u: u { int64: height },
+            },
+        ];
+        assert_eq!(keys.len(), values.len());
+
+        let mut list = mpv_node_list {
+            num: keys.len().try_into().unwrap(),
+            values: values.as_mut_ptr(),
+            keys: keys.as_mut_ptr(),
+        };
+        let mut args = mpv_node {
+            format: mpv_format::MPV_FORMAT_NODE_MAP,
+            u: u {
+                list: addr_of_mut!(list),
+            },
+        };
+        let error = unsafe { mpv_command_node(CTX, addr_of_mut!(args), null_mut()) };
+        if error < 0 {
+            log_code(error);
+        }
+
+        unsafe {
+            _ = keys.map(|key| CString::from_raw(key));
+            _ = CString::from_raw(value1);
+            _ = CString::from_raw(value3);
+            _ = CString::from_raw(value4);
+        }
     }
 
-    unsafe {
-        _ = keys.map(|key| CString::from_raw(key));
-        _ = CString::from_raw(value1);
-        _ = CString::from_raw(value3);
-        _ = CString::from_raw(value4);
+    fn remove_overlay(&self, id: i64) {
+        let mut keys =
+            [c"name", c"id", c"format", c"data"].map(|key| CString::from(key).into_raw());
+        let value1 = CString::from(c"osd-overlay").into_raw();
+        let value3 = CString::from(c"none").into_raw();
+        let value4 = CString::from(c"").into_raw();
+        let mut values = [
+            mpv_node {
+                format: mpv_format::MPV_FORMAT_STRING,
+                u: u { string: value1 },
+            },
+            mpv_node {
+                format: mpv_format::MPV_FORMAT_INT64,
+                u: u { int64: id },
+            },
+            mpv_node {
+                format: mpv_format::MPV_FORMAT_STRING,
+                u: u { string: value3 },
+            },
+            mpv_node {
+                format: mpv_format::MPV_FORMAT_STRING,
+                u: u { string: value4 },
+            },
+        ];
+        assert_eq!(keys.len(), values.len());
+
+        let mut list = mpv_node_list {
+            num: keys.len().try_into().unwrap(),
+            values: values.as_mut_ptr(),
+            keys: keys.as_mut_ptr(),
+        };
+        let mut args = mpv_node {
+            format: mpv_format::MPV_FORMAT_NODE_MAP,
+            u: u {
+                list: addr_of_mut!(list),
+            },
+        };
+        let error = unsafe { mpv_command_node(CTX, addr_of_mut!(args), null_mut()) };
+        if error < 0 {
+            log_code(error);
+        }
+
+        unsafe {
+            _ = keys.map(|key| CString::from_raw(key));
+            _ = CString::from_raw(value1);
+            _ = CString::from_raw(value3);
+            _ = CString::from_raw(value4);
+        }
     }
-}
 
-pub fn get_property_f64(name: &CStr) -> Option<f64> {
-    let mut data = unsafe { MaybeUninit::<f64>::uninit().assume_init() };
-    let error = unsafe {
-        mpv_get_property(
-            CTX,
-            name.as_ptr(),
-            mpv_format::MPV_FORMAT_DOUBLE,
-            addr_of_mut!(data).cast(),
-        )
-    };
-    if error < 0 {
-        log_code(error);
-        None
-    } else {
-        Some(data)
+    fn get_property_f64(&self, name: &str) -> Option<f64> {
+        let name = CString::new(name).unwrap();
+        let mut data = unsafe { MaybeUninit::<f64>::uninit().assume_init() };
+        let error = unsafe {
+            mpv_get_property(
+                CTX,
+                name.as_ptr(),
+                mpv_format::MPV_FORMAT_DOUBLE,
+                addr_of_mut!(data).cast(),
+            )
+        };
+        if error < 0 {
+            log_code(error);
+            None
+        } else {
+            Some(data)
+        }
     }
-}
 
-pub fn get_property_string(name: &CStr) -> Option<String> {
-    let mut data = unsafe { MaybeUninit::<*mut c_char>::uninit().assume_init() };
-    let error = unsafe {
-        mpv_get_property(
-            CTX,
-            name.as_ptr(),
-            mpv_format::MPV_FORMAT_STRING,
-            addr_of_mut!(data).cast(),
-        )
-    };
-    if error < 0 {
-        log_code(error);
-        None
-    } else {
-        let value = unsafe { CStr::from_ptr(data) }
-            .to_str()
-            .unwrap()
-            .to_string();
-        unsafe { mpv_free(data.cast()) };
-        Some(value)
+    fn get_property_string(&self, name: &str) -> Option<String> {
+        let name = CString::new(name).unwrap();
+        let mut data = unsafe { MaybeUninit::<*mut c_char>::uninit().assume_init() };
+        let error = unsafe {
+            mpv_get_property(
+                CTX,
+                name.as_ptr(),
+                mpv_format::MPV_FORMAT_STRING,
+                addr_of_mut!(data).cast(),
+            )
+        };
+        if error < 0 {
+            log_code(error);
+            None
+        } else {
+            let value = unsafe { CStr::from_ptr(data) }
+                .to_str()
+                .unwrap()
+                .to_string();
+            unsafe { mpv_free(data.cast()) };
+            Some(value)
+        }
     }
-}
 
-pub fn expand_path(path: &str) -> Result<String> {
-    unsafe {
-        let arg2 = CString::new(path).unwrap();
-        let mut args = [c"expand-path".as_ptr(), arg2.as_ptr(), null()];
-        let mut result = MaybeUninit::<mpv_node>::uninit().assume_init();
-        let error = mpv_command_ret(CTX, args.as_mut_ptr(), addr_of_mut!(result));
+    fn expand_path(&self, path: &str) -> Result<String> {
+        unsafe {
+            let arg2 = CString::new(path).unwrap();
+            let mut args = [c"expand-path".as_ptr(), arg2.as_ptr(), null()];
+            let mut result = MaybeUninit::<mpv_node>::uninit().assume_init();
+            let error = mpv_command_ret(CTX, args.as_mut_ptr(), addr_of_mut!(result));
+            if error < 0 {
+                return Err(anyhow!(
+                    "{}",
+                    CStr::from_ptr(mpv_error_string(error)).to_str().unwrap()
+                ));
+            }
+            assert_eq!(result.format, mpv_format::MPV_FORMAT_STRING);
+            let path = CStr::from_ptr(result.u.string)
+                .to_str()
+                .unwrap()
+                .to_string();
+            mpv_free_node_contents(addr_of_mut!(result));
+            Ok(path)
+        }
+    }
+
+    fn osd_message(&self, text: &str) {
+        let arg2 = CString::new(text).unwrap();
+        let mut args = [c"show-text".as_ptr(), arg2.as_ptr(), null()];
+        let error = unsafe { mpv_command(CTX, args.as_mut_ptr()) };
+        if error < 0 {
+            log_code(error);
+        }
+    }
+
+    fn command(&self, args: &[&str]) {
+        let args = args
+            .iter()
+            .map(|&arg| CString::new(arg).unwrap())
+            .collect::<Vec<_>>();
+        let mut args = args
+            .iter()
+            .map(|arg| arg.as_ptr())
+            .chain([null()])
+            .collect::<Vec<_>>();
+        let error = unsafe { mpv_command(CTX, args.as_mut_ptr()) };
         if error < 0 {
-            return Err(anyhow!(
-                "{}",
-                CStr::from_ptr(mpv_error_string(error)).to_str().unwrap()
-            ));
+            log_code(error);
         }
-        assert_eq!(result.format, mpv_format::MPV_FORMAT_STRING);
-        let path = CStr::from_ptr(result.u.string)
-            .to_str()
-            .unwrap()
-            .to_string();
-        mpv_free_node_contents(addr_of_mut!(result));
-        Ok(path)
     }
 }
 
-pub fn osd_message(text: &str) {
-    let arg2 = CString::new(text).unwrap();
-    let mut args = [c"show-text".as_ptr(), arg2.as_ptr(), null()];
-    let error = unsafe { mpv_command(CTX, args.as_mut_ptr()) };
-    if error < 0 {
-        log_code(error);
+/// string pairs of a `MPV_FORMAT_NODE_MAP` node
+fn node_map(node: &mpv_node) -> Vec<(String, String)> {
+    assert_eq!(node.format, mpv_format::MPV_FORMAT_NODE_MAP);
+    let list = unsafe { &*node.u.list };
+    if list.num == 0 {
+        return Vec::new();
     }
+    let num = list.num.try_into().unwrap();
+    let keys = unsafe { from_raw_parts(list.keys, num) };
+    let values = unsafe { from_raw_parts(list.values, num) };
+    keys.iter()
+        .zip(values)
+        .filter_map(|(key, value)| {
+            assert_eq!(value.format, mpv_format::MPV_FORMAT_STRING);
+            let key = unsafe { CStr::from_ptr(key.cast()) }.to_str();
+            let value = unsafe { CStr::from_ptr(value.u.string) }.to_str();
+            match (key, value) {
+                (Ok(key), Ok(value)) => Some((key.to_string(), value.to_string())),
+                (Err(error), _) | (_, Err(error)) => {
+                    log_error(&error.into());
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+pub fn osd_overlay(id: i64, data: &str, width: i64, height: i64) {
+    host().osd_overlay(id, data, width, height);
+}
+
+pub fn remove_overlay(id: i64) {
+    host().remove_overlay(id);
+}
+
+pub fn get_property_f64(name: &str) -> Option<f64> {
+    host().get_property_f64(name)
+}
+
+pub fn get_property_string(name: &str) -> Option<String> {
+    host().get_property_string(name)
+}
+
+pub fn expand_path(path: &str) -> Result<String> {
+    host().expand_path(path)
+}
+
+pub fn osd_message(text: &str) {
+    host().osd_message(text);
+}
+
+pub fn command(args: &[&str]) {
+    host().command(args);
 }
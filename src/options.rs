@@ -1,13 +1,20 @@
-use crate::{CLIENT_NAME, dandanplay::Source, log::log_error, mpv::expand_path};
-use anyhow::{Result, anyhow};
+use crate::{
+    CLIENT_NAME,
+    chinese::Convert,
+    dandanplay::{Danmaku, Source},
+    log::log_error,
+    mpv::expand_path,
+};
+use anyhow::{Error, Result, anyhow};
+use regex::Regex;
 use serde::Deserialize;
 use std::{
     collections::HashSet,
     fs::File,
     io::{BufRead, BufReader, ErrorKind},
-    sync::{Arc, LazyLock},
+    sync::{LazyLock, RwLock},
 };
-use tokio::sync::Mutex;
+use tracing::warn;
 
 #[derive(Deserialize)]
 struct BilibiliFilterRule {
@@ -16,18 +23,40 @@ struct BilibiliFilterRule {
     opened: bool,
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone)]
 pub struct Options {
     pub font_size: f64,
     pub transparency: u8,
     pub reserved_space: f64,
     pub speed: f64,
     pub no_overlap: bool,
-    pub proxy: &'static str,
-    pub user_agent: &'static str,
-    pub log: &'static str,
-    pub app_id: &'static str,
-    pub app_secret: &'static str,
+    pub proxy: String,
+    pub user_agent: String,
+    pub log: String,
+    pub app_id: String,
+    pub app_secret: String,
+    pub xml_pattern: String,
+    pub api_url: String,
+    pub api_auth: bool,
+    pub cache_ttl: u64,
+    pub merge_window: f64,
+    pub merge_similarity: f64,
+    pub merge_boost: f64,
+    pub density: usize,
+    pub density_area: f64,
+    pub avoid_subtitles: bool,
+    pub font_name: String,
+    pub bold: bool,
+    pub outline: f64,
+    /// `0xRRGGBB`
+    pub outline_color: u32,
+    pub shadow: f64,
+    pub scroll_tags: String,
+    pub top_tags: String,
+    pub bottom_tags: String,
+    pub convert: Convert,
+    /// comma separated sources whose related pages are enabled unless toggled, `all` or `none`
+    pub related: String,
 }
 
 impl Default for Options {
@@ -38,11 +67,31 @@ impl Default for Options {
             reserved_space: 0.,
             speed: 1.,
             no_overlap: true,
-            proxy: "",
-            user_agent: "libmpv",
-            log: "false",
-            app_id: "",
-            app_secret: "",
+            proxy: String::new(),
+            user_agent: "libmpv".into(),
+            log: "false".into(),
+            app_id: String::new(),
+            app_secret: String::new(),
+            xml_pattern: "{name}.xml".into(),
+            api_url: "https://api.dandanplay.net".into(),
+            api_auth: true,
+            cache_ttl: 0,
+            merge_window: 0.,
+            merge_similarity: 1.,
+            merge_boost: 0.1,
+            density: 0,
+            density_area: 1.,
+            avoid_subtitles: true,
+            font_name: String::new(),
+            bold: true,
+            outline: 1.5,
+            outline_color: 0,
+            shadow: 0.,
+            scroll_tags: String::new(),
+            top_tags: String::new(),
+            bottom_tags: String::new(),
+            convert: Convert::No,
+            related: "all".into(),
         }
     }
 }
@@ -50,11 +99,214 @@ impl Default for Options {
 #[derive(Default)]
 pub struct Filter {
     pub keywords: Vec<String>,
+    pub regexes: Vec<Regex>,
+    pub users: HashSet<String>,
     pub sources: HashSet<Source>,
-    pub sources_rt: Mutex<Option<HashSet<Source>>>,
 }
 
-pub fn read_options() -> Result<Option<(Options, Arc<Filter>)>> {
+impl Filter {
+    /// whether the comment matches any keyword, regex or blocked user
+    pub fn is_filtered(&self, comment: &Danmaku) -> bool {
+        self.keywords
+            .iter()
+            .any(|pat| comment.message.contains(pat))
+            || self.regexes.iter().any(|re| re.is_match(&comment.message))
+            || self.users.contains(&comment.user)
+    }
+
+    /// whether the comment is filtered or comes from a blocked source
+    pub fn is_blocked(&self, comment: &Danmaku) -> bool {
+        self.sources.contains(&comment.source) || self.is_filtered(comment)
+    }
+
+    fn add_regex(&mut self, pattern: &str) -> Result<()> {
+        let regex =
+            Regex::new(pattern).map_err(|e| anyhow!("invalid filter regex {}: {}", pattern, e))?;
+        self.regexes.push(regex);
+        Ok(())
+    }
+
+    /// forget the values of a list option
+    fn clear(&mut self, key: &str) {
+        match key {
+            "filter" => self.keywords.clear(),
+            "filter_regex" => self.regexes.clear(),
+            "filter_user" => self.users.clear(),
+            "filter_source" => self.sources.clear(),
+            _ => (),
+        }
+    }
+
+    fn add_bilibili(&mut self, path: &str) -> Result<()> {
+        let rules = serde_json::from_reader::<_, Vec<BilibiliFilterRule>>(BufReader::new(
+            File::open(expand_path(path)?)?,
+        ))?;
+        for rule in rules.into_iter().filter(|r| r.opened) {
+            match rule.r#type {
+                0 => self.keywords.push(rule.filter),
+                1 => {
+                    // regex rules may be exported as /pattern/
+                    let pattern = rule.filter.as_str();
+                    if let Err(error) = self.add_regex(
+                        pattern
+                            .strip_prefix('/')
+                            .and_then(|p| p.strip_suffix('/'))
+                            .unwrap_or(pattern),
+                    ) {
+                        log_error(&error);
+                    }
+                }
+                2 => _ = self.users.insert(rule.filter),
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Options {
+    /// whether related comments from `source` are fetched
+    pub fn is_related(&self, source: Source) -> bool {
+        match self.related.as_str() {
+            "all" => true,
+            "none" => false,
+            related => related.split(',').any(|s| Source::from(s) == source),
+        }
+    }
+
+    /// apply a single `key=value` pair, list options are appended to `filter`
+    fn set(&mut self, filter: &mut Filter, key: &str, value: &str) -> Result<()> {
+        let invalid = || anyhow!("option {}: invalid value {}", key, value);
+        let yes_no = |value| match value {
+            "yes" => Ok(true),
+            "no" => Ok(false),
+            _ => Err(invalid()),
+        };
+
+        match key {
+            "font_size" => {
+                self.font_size = value.parse().ok().filter(|&f| f > 0.).ok_or_else(invalid)?
+            }
+            "transparency" => self.transparency = value.parse().map_err(|_| invalid())?,
+            "reserved_space" => {
+                self.reserved_space = value
+                    .parse()
+                    .ok()
+                    .filter(|r| (0. ..1.).contains(r))
+                    .ok_or_else(invalid)?
+            }
+            "speed" => self.speed = value.parse().ok().filter(|&s| s > 0.).ok_or_else(invalid)?,
+            "no_overlap" => self.no_overlap = yes_no(value)?,
+            "avoid_subtitles" => self.avoid_subtitles = yes_no(value)?,
+            "font_name" => self.font_name = value.into(),
+            "bold" => self.bold = yes_no(value)?,
+            "outline" => {
+                self.outline = value
+                    .parse()
+                    .ok()
+                    .filter(|&o| o >= 0.)
+                    .ok_or_else(invalid)?
+            }
+            "outline_color" => {
+                self.outline_color = Some(value.trim_start_matches('#'))
+                    .filter(|hex| hex.len() == 6)
+                    .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                    .ok_or_else(invalid)?
+            }
+            "shadow" => {
+                self.shadow = value
+                    .parse()
+                    .ok()
+                    .filter(|&s| s >= 0.)
+                    .ok_or_else(invalid)?
+            }
+            "scroll_tags" => self.scroll_tags = value.into(),
+            "top_tags" => self.top_tags = value.into(),
+            "bottom_tags" => self.bottom_tags = value.into(),
+            "proxy" if value.is_empty() => (),
+            "proxy" => {
+                if !value.starts_with("http") || reqwest::Proxy::all(value).is_err() {
+                    return Err(invalid());
+                }
+                self.proxy = value.into();
+            }
+            "user_agent" | "log" | "app_id" | "app_secret" | "api_url" if value.is_empty() => (),
+            "user_agent" => self.user_agent = value.into(),
+            "log" => self.log = value.into(),
+            "app_id" => self.app_id = value.into(),
+            "app_secret" => self.app_secret = value.into(),
+            "api_url" => self.api_url = value.into(),
+            "api_auth" => self.api_auth = yes_no(value)?,
+            "cache_ttl" => self.cache_ttl = value.parse().map_err(|_| invalid())?,
+            "density" => self.density = value.parse().map_err(|_| invalid())?,
+            "density_area" => {
+                self.density_area = value
+                    .parse()
+                    .ok()
+                    .filter(|a| *a > 0. && *a <= 1.)
+                    .ok_or_else(invalid)?
+            }
+            "merge_window" => {
+                self.merge_window = value
+                    .parse()
+                    .ok()
+                    .filter(|&w| w >= 0.)
+                    .ok_or_else(invalid)?
+            }
+            "merge_similarity" => {
+                self.merge_similarity = value
+                    .parse()
+                    .ok()
+                    .filter(|s| (0. ..=1.).contains(s))
+                    .ok_or_else(invalid)?
+            }
+            "merge_boost" => {
+                self.merge_boost = value
+                    .parse()
+                    .ok()
+                    .filter(|&b| b >= 0.)
+                    .ok_or_else(invalid)?
+            }
+            "xml_pattern" => self.xml_pattern = value.into(),
+            "related" => {
+                if value != "all"
+                    && value != "none"
+                    && value.split(',').any(|s| Source::from(s) == Source::Unknown)
+                {
+                    return Err(invalid());
+                }
+                self.related = value.into()
+            }
+            "convert" => {
+                self.convert = match value {
+                    "no" => Convert::No,
+                    "simplified" => Convert::Simplified,
+                    "traditional" => Convert::Traditional,
+                    _ => return Err(invalid()),
+                }
+            }
+            "filter" | "filter_regex" | "filter_user" | "filter_source" | "filter_bilibili"
+                if value.is_empty() => {}
+            "filter" => filter.keywords.extend(value.split(',').map(Into::into)),
+            "filter_regex" => filter.add_regex(value)?,
+            "filter_user" => filter.users.extend(value.split(',').map(Into::into)),
+            "filter_source" => filter.sources.extend(
+                value
+                    .split(',')
+                    .map(Source::from)
+                    .filter(|&s| s != Source::Unknown),
+            ),
+            "filter_bilibili" => filter
+                .add_bilibili(value)
+                .map_err(|e| anyhow!("option filter_bilibili: {}", e))?,
+            _ => warn!("unknown option {}", key),
+        }
+        Ok(())
+    }
+}
+
+/// `key=value` pairs of `script-opts/<client>.conf`
+fn read_file() -> Result<Vec<(String, String)>> {
     let path = expand_path(&format!(
         "~~/script-opts/{}.conf",
         CLIENT_NAME.get().unwrap_or(&"".to_string())
@@ -63,93 +315,88 @@ pub fn read_options() -> Result<Option<(Options, Arc<Filter>)>> {
         Ok(file) => file,
         Err(error) => {
             return if error.kind() == ErrorKind::NotFound {
-                Ok(None)
+                Ok(Vec::new())
             } else {
                 Err(error.into())
             };
         }
     };
 
-    let mut opts = Options::default();
-    let mut filter = Filter::default();
+    let mut pairs = Vec::new();
     for line in BufReader::new(file).lines() {
         let line = line?;
         if line.starts_with('#') {
             continue;
         }
         if let Some((k, v)) = line.split_once('=') {
-            match k {
-                "font_size" => {
-                    if let Some(f) = v.parse().ok().filter(|&f| f > 0.) {
-                        opts.font_size = f;
-                    }
-                }
-                "transparency" => {
-                    if let Ok(t) = v.parse() {
-                        opts.transparency = t;
-                    }
-                }
-                "reserved_space" => {
-                    if let Some(r) = v.parse().ok().filter(|r| (0. ..1.).contains(r)) {
-                        opts.reserved_space = r;
-                    }
-                }
-                "speed" => {
-                    if let Some(s) = v.parse().ok().filter(|s| *s > 0.) {
-                        opts.speed = s;
-                    }
-                }
-                "no_overlap" => match v {
-                    "yes" => opts.no_overlap = true,
-                    "no" => opts.no_overlap = false,
-                    _ => (),
-                },
-                "proxy" if !v.is_empty() && v.starts_with("http") => {
-                    opts.proxy = Box::leak(v.to_string().into_boxed_str());
-                }
-                "user_agent" if !v.is_empty() => {
-                    opts.user_agent = Box::leak(v.to_string().into_boxed_str());
-                }
-                "log" if !v.is_empty() => {
-                    opts.log = Box::leak(v.to_string().into_boxed_str());
-                }
-                "app_id" if !v.is_empty() => {
-                    opts.app_id = Box::leak(v.to_string().into_boxed_str());
-                }
-                "app_secret" if !v.is_empty() => {
-                    opts.app_secret = Box::leak(v.to_string().into_boxed_str());
-                }
-                "filter" if !v.is_empty() => filter.keywords.extend(v.split(',').map(Into::into)),
-                "filter_source" if !v.is_empty() => filter.sources.extend(
-                    v.split(',')
-                        .map(Source::from)
-                        .filter(|&s| s != Source::Unknown),
-                ),
-                "filter_bilibili" if !v.is_empty() => match (|| -> Result<_> {
-                    Ok(serde_json::from_reader::<_, Vec<BilibiliFilterRule>>(
-                        BufReader::new(File::open(expand_path(v)?)?),
-                    )?)
-                })() {
-                    Ok(rules) => filter.keywords.extend(
-                        rules
-                            .into_iter()
-                            .filter(|r| r.r#type == 0 && r.opened)
-                            .map(|r| r.filter),
-                    ),
-                    Err(error) => log_error(&anyhow!("option filter_bilibili: {}", error)),
-                },
-                _ => (),
-            }
+            pairs.push((k.to_string(), v.to_string()));
         }
     }
-    Ok(Some((opts, Arc::new(filter))))
+    Ok(pairs)
 }
 
-pub static OPTIONS: LazyLock<Options> = LazyLock::new(|| {
-    read_options()
-        .map_err(|e| crate::log::log_error(&e))
-        .ok()
-        .flatten()
-        .unwrap_or_default()
-        .0
-});
+/// read the options file and apply the `script-opts` overrides on top of it, list options
+/// set through `script-opts` replace the ones from the file and empty values are ignored,
+/// invalid values are skipped and returned along with the result, unknown keys are only logged
+pub fn load(overrides: &[(String, String)]) -> (Options, Filter, Vec<Error>) {
+    let mut opts = Options::default();
+    let mut filter = Filter::default();
+    let mut errors = Vec::new();
+
+    let pairs = read_file().unwrap_or_else(|error| {
+        errors.push(error);
+        Vec::new()
+    });
+    for (k, v) in &pairs {
+        if let Err(error) = opts.set(&mut filter, k, v) {
+            errors.push(error);
+        }
+    }
+
+    let mut cleared = HashSet::new();
+    for (k, v) in overrides.iter().filter(|(_, v)| !v.is_empty()) {
+        if cleared.insert(k.as_str()) {
+            filter.clear(k);
+        }
+        if let Err(error) = opts.set(&mut filter, k, v) {
+            errors.push(error);
+        }
+    }
+    (opts, filter, errors)
+}
+
+static OPTIONS: LazyLock<RwLock<Options>> = LazyLock::new(Default::default);
+
+/// the options currently in effect
+pub fn get() -> Options {
+    OPTIONS.read().unwrap().clone()
+}
+
+pub fn set(options: Options) {
+    *OPTIONS.write().unwrap() = options;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dandanplay::Mode;
+
+    #[test]
+    fn test_filter() {
+        let mut filter = Filter {
+            keywords: vec!["spoiler".to_string()],
+            users: HashSet::from(["abcdef12".to_string()]),
+            ..Default::default()
+        };
+        filter.add_regex("^2{3,}$").unwrap();
+        assert!(filter.add_regex("(").is_err());
+
+        let comment =
+            |message, user| Danmaku::new(message, 0., Mode::Scroll, 0, Source::Bilibili, user);
+        assert_eq!(filter.regexes.len(), 1);
+        assert!(filter.is_filtered(&comment("big spoiler", "1")));
+        assert!(filter.is_filtered(&comment("22222", "1")));
+        assert!(!filter.is_filtered(&comment("2333", "1")));
+        assert!(filter.is_filtered(&comment("hello", "abcdef12")));
+    }
+}
@@ -1,11 +1,11 @@
-use crate::options::OPTIONS;
-use crate::utils::CLIENT;
+use crate::options;
+use crate::utils::client;
 use anyhow::{Result, anyhow};
-use reqwest::RequestBuilder;
+use reqwest::{Method, Response};
+use serde_json::Value;
 use sha2::{Digest, Sha256};
 use std::time::{SystemTime, UNIX_EPOCH};
-
-const BASE_URL: &str = "https://api.dandanplay.net";
+use tracing::warn;
 
 /// base64(sha256(AppId + Timestamp + Path + AppSecret))
 fn calculate_signature(app_id: &str, timestamp: i64, path: &str, app_secret: &str) -> String {
@@ -22,10 +22,14 @@ fn get_timestamp() -> i64 {
         .as_secs() as i64
 }
 
-fn build_auth_headers(path: &str) -> Result<[(String, String); 3]> {
-    let options = *OPTIONS;
-    let app_id = options.app_id;
-    let app_secret = options.app_secret;
+fn build_auth_headers(path: &str) -> Result<Vec<(String, String)>> {
+    let options = options::get();
+    let app_id = &options.app_id;
+    let app_secret = &options.app_secret;
+
+    if !options.api_auth {
+        return Ok(Vec::new());
+    }
 
     if app_id.is_empty() || app_secret.is_empty() {
         return Err(anyhow!("app_id and app_secret must be configured"));
@@ -38,49 +42,74 @@ fn build_auth_headers(path: &str) -> Result<[(String, String); 3]> {
 
     let signature = calculate_signature(app_id, timestamp, &signature_path, app_secret);
 
-    Ok([
+    Ok(vec![
         ("X-AppId".to_string(), app_id.to_string()),
         ("X-Signature".to_string(), signature),
         ("X-Timestamp".to_string(), timestamp.to_string()),
     ])
 }
 
+/// base urls from the comma separated `api_url` option, in the order they are tried
+fn endpoints(api_url: &str) -> impl Iterator<Item = &str> {
+    api_url
+        .split(',')
+        .map(|url| url.trim().trim_end_matches('/'))
+        .filter(|url| !url.is_empty())
+}
+
 pub struct DandanplayService;
 
 impl DandanplayService {
-    pub fn get(path: &str) -> Result<RequestBuilder> {
-        let headers = build_auth_headers(path)?;
-        let url = format!("{}{}", BASE_URL, path);
-        let mut request = CLIENT.get(&url);
-
-        for (key, value) in headers {
-            request = request.header(&key, value);
-        }
-
-        Ok(request)
+    pub async fn get(path: &str) -> Result<Response> {
+        Self::send(Method::GET, path, None).await
     }
 
-    pub fn post(path: &str) -> Result<RequestBuilder> {
-        let headers = build_auth_headers(path)?;
-        let url = format!("{}{}", BASE_URL, path);
-        let mut request = CLIENT.post(&url);
+    pub async fn post(path: &str, json: &Value) -> Result<Response> {
+        Self::send(Method::POST, path, Some(json)).await
+    }
 
-        for (key, value) in headers {
-            request = request.header(&key, value);
+    /// try every endpoint in turn until one responds without a server error
+    async fn send(method: Method, path: &str, json: Option<&Value>) -> Result<Response> {
+        // checked here rather than at startup, local danmaku files need no credentials
+        Self::ensure_auth_configured()?;
+        let mut last_error = anyhow!("no api_url configured");
+
+        for base_url in endpoints(&options::get().api_url) {
+            let url = format!("{}{}", base_url, path);
+            let mut request = client().request(method.clone(), &url);
+
+            for (key, value) in build_auth_headers(path)? {
+                request = request.header(&key, value);
+            }
+            if let Some(json) = json {
+                request = request.json(json);
+            }
+
+            match request.send().await {
+                Ok(res) if !res.status().is_server_error() => return Ok(res),
+                Ok(res) => {
+                    warn!("Request to {} failed, Status: {:?}", base_url, res.status());
+                    last_error = anyhow!("request failed, status: {}", res.status());
+                }
+                Err(error) => {
+                    warn!("Request to {} failed: {}", base_url, error);
+                    last_error = error.into();
+                }
+            }
         }
 
-        Ok(request)
+        Err(last_error)
     }
 
     pub fn is_auth_configured() -> bool {
-        let options = *OPTIONS;
-        !options.app_id.is_empty() && !options.app_secret.is_empty()
+        let options = options::get();
+        !options.api_auth || (!options.app_id.is_empty() && !options.app_secret.is_empty())
     }
 
     pub fn ensure_auth_configured() -> Result<()> {
         if !Self::is_auth_configured() {
             return Err(anyhow!(
-                "Authentication not configured. Please set app_id and app_secret in config file."
+                "app_id and app_secret not configured. Please set them in config file, or set api_auth=no for servers without authentication."
             ));
         }
         Ok(())
@@ -106,4 +135,14 @@ mod tests {
                 .all(|c| c.is_ascii_alphanumeric() || c == '+' || c == '/' || c == '=')
         );
     }
+
+    #[test]
+    fn test_endpoints() {
+        let urls =
+            endpoints(" https://api.dandanplay.net/, ,http://127.0.0.1:8080").collect::<Vec<_>>();
+        assert_eq!(
+            urls,
+            ["https://api.dandanplay.net", "http://127.0.0.1:8080"]
+        );
+    }
 }
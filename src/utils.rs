@@ -1,31 +1,56 @@
-use crate::options;
+use crate::{
+    emby::original_file,
+    mpv::expand_path,
+    options::{self, Options},
+};
 use anyhow::{Result, anyhow};
 use hex::encode;
 use md5::{Digest, Md5};
-use reqwest::Client;
-use serde::{Deserialize, Serialize};
+use reqwest::{
+    Client, RequestBuilder, StatusCode,
+    header::{CONTENT_RANGE, RANGE},
+};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
 use std::{
     borrow::Borrow,
     collections::{HashMap, VecDeque},
     hash::Hash,
-    sync::LazyLock,
+    path::Path,
+    sync::Mutex,
 };
 use tracing::{error, info};
 
-pub(crate) static CLIENT: LazyLock<Client> = LazyLock::new(build);
-
-fn build() -> reqwest::Client {
-    let options = *options::OPTIONS;
+/// the client along with the proxy and user agent it was built with
+static CLIENT: Mutex<Option<(String, String, Client)>> = Mutex::new(None);
+
+/// shared http client, rebuilt when `proxy` or `user_agent` change
+pub(crate) fn client() -> Client {
+    let options = options::get();
+    let mut cached = CLIENT.lock().unwrap();
+    match &*cached {
+        Some((proxy, user_agent, client))
+            if *proxy == options.proxy && *user_agent == options.user_agent =>
+        {
+            client.clone()
+        }
+        _ => {
+            let client = build(&options);
+            *cached = Some((options.proxy, options.user_agent, client.clone()));
+            client
+        }
+    }
+}
 
+fn build(options: &Options) -> reqwest::Client {
     if options.proxy.is_empty() {
         Client::builder()
-            .user_agent(options.user_agent)
+            .user_agent(&options.user_agent)
             .build()
             .expect("Failed to build client")
     } else {
         Client::builder()
-            .proxy(reqwest::Proxy::all(options.proxy).unwrap())
-            .user_agent(options.user_agent)
+            .proxy(reqwest::Proxy::all(&options.proxy).unwrap())
+            .user_agent(&options.user_agent)
             .build()
             .expect("Failed to build client")
     }
@@ -44,20 +69,50 @@ pub fn is_http_link(url: &str) -> bool {
 // Set Limit of buffer size
 const MAX_SIZE: usize = 16 * 1024 * 1024;
 
-pub async fn get_stream_hash(path: &str) -> Result<String> {
+/// md5 of the first 16MiB of a video, of the whole video when it is smaller
+pub struct FileHash {
+    pub hash: String,
+    /// size of the whole video when known
+    pub size: Option<u64>,
+}
+
+pub async fn get_stream_hash(path: &str) -> Result<FileHash> {
+    // transcoded streams differ from the file dandanplay indexed
+    if let Some(request) = original_file(path) {
+        match get_response_hash(request).await {
+            Ok(file_hash) => return Ok(file_hash),
+            Err(error) => error!(
+                "Failed to hash the original file, hashing the stream: {}",
+                error
+            ),
+        }
+    }
+    get_response_hash(client().get(path)).await
+}
+
+async fn get_response_hash(request: RequestBuilder) -> Result<FileHash> {
     use futures::StreamExt;
 
-    let response = CLIENT.get(path).send().await?;
+    let response = request
+        .header(RANGE, format!("bytes=0-{}", MAX_SIZE - 1))
+        .send()
+        .await?;
 
     // check status Code
-    if !response.status().is_success() {
-        error!(
-            "Failed to fetch data from server, Status: {}",
-            response.status()
-        );
-
-        return Err(anyhow!("Failed to fetch data from server"));
-    }
+    let size = match response.status() {
+        StatusCode::PARTIAL_CONTENT => response
+            .headers()
+            .get(CONTENT_RANGE)
+            .and_then(|range| range.to_str().ok())
+            .and_then(content_range_size),
+        // the range was ignored and the whole file is sent
+        StatusCode::OK => response.content_length(),
+        status => {
+            error!("Failed to fetch data from server, Status: {}", status);
+
+            return Err(anyhow!("Failed to fetch data from server"));
+        }
+    };
 
     let mut stream = response.bytes_stream();
     let mut downloaded: usize = 0;
@@ -84,14 +139,32 @@ pub async fn get_stream_hash(path: &str) -> Result<String> {
     }
 
     let result = hasher.finalize();
-    if downloaded < MAX_SIZE {
-        error!("File too small, less than 16MiB");
-        return Err(anyhow!("file too small"));
-    }
+    // the stream ended early, so the whole file was hashed unless the server said otherwise
+    let size = if downloaded < MAX_SIZE {
+        if let Some(size) = size.filter(|&size| size > downloaded as u64) {
+            return Err(anyhow!(
+                "stream ended after {} of {} bytes",
+                downloaded,
+                size
+            ));
+        }
+        info!("File smaller than 16MiB, hashing the whole file");
+        Some(downloaded as u64)
+    } else {
+        size
+    };
 
     info!("Get streaming file hash: {}", encode(result));
 
-    Ok(encode(result))
+    Ok(FileHash {
+        hash: encode(result),
+        size,
+    })
+}
+
+/// total size in a `bytes 0-16777215/123456789` content range, unknown for `*`
+fn content_range_size(content_range: &str) -> Option<u64> {
+    content_range.rsplit_once('/')?.1.trim().parse().ok()
 }
 
 #[derive(Debug, Deserialize)]
@@ -147,24 +220,27 @@ pub fn get_localfile_name(path: &str) -> String {
         .to_string()
 }
 
-pub fn get_localfile_hash(path: &str) -> Result<String> {
+pub fn get_localfile_hash(path: &str) -> Result<FileHash> {
     use std::fs::File;
     use std::io::Read;
     use std::path::PathBuf;
 
-    let mut file = File::open(PathBuf::from(path))?;
-    let mut buffer = vec![0u8; MAX_SIZE];
-    let bytes_read = file.read(&mut buffer)?;
+    let file = File::open(PathBuf::from(path))?;
+    let size = file.metadata()?.len();
+    let mut buffer = Vec::with_capacity(MAX_SIZE);
+    file.take(MAX_SIZE as u64).read_to_end(&mut buffer)?;
 
-    if bytes_read < MAX_SIZE {
-        error!("File too small, less than 16MiB");
-        return Err(anyhow!("file too small"));
+    if buffer.len() < MAX_SIZE {
+        info!("File smaller than 16MiB, hashing the whole file");
     }
 
     let mut hasher = Md5::new();
-    hasher.update(&buffer[..bytes_read]);
+    hasher.update(&buffer);
 
-    Ok(encode(hasher.finalize()))
+    Ok(FileHash {
+        hash: encode(hasher.finalize()),
+        size: Some(size),
+    })
 }
 
 use std::time::{Duration, SystemTime};
@@ -185,6 +261,13 @@ pub struct AnimeOffset {
 pub struct Linkage {
     pub items: HashMap<String, LimitedHashMap<String, TimesId>>,
     pub seasons: HashMap<String, LimitedHashMap<String, AnimeOffset>>,
+    /// stored in a file of its own, so that `database` files of older versions still decode
+    #[serde(skip, default = "hashes")]
+    pub hashes: LimitedHashMap<String, TimesId>,
+}
+
+fn hashes() -> LimitedHashMap<String, TimesId> {
+    LimitedHashMap::new(1000)
 }
 
 impl Default for Linkage {
@@ -198,6 +281,7 @@ impl Linkage {
         Linkage {
             items: HashMap::new(),
             seasons: HashMap::new(),
+            hashes: hashes(),
         }
     }
 
@@ -227,6 +311,18 @@ impl Linkage {
         self.seasons.get(host_key)?.get(season_id).copied()
     }
 
+    pub fn insert_hashes(&mut self, hash: &str, epid: usize) {
+        let timestamped_value = TimesId {
+            epid,
+            last_updated: SystemTime::now(),
+        };
+        self.hashes.insert(hash.to_string(), timestamped_value);
+    }
+
+    pub fn get_hashes(&self, hash: &str) -> Option<usize> {
+        self.hashes.get(hash).map(|tv| tv.epid)
+    }
+
     pub fn clean_expired_entries(&mut self, expiration_duration: Duration) {
         let now = SystemTime::now();
         self.items.retain(|_, inner_map| {
@@ -240,50 +336,122 @@ impl Linkage {
     }
 
     pub async fn save_as_bincode(&self) -> Result<()> {
-        use crate::mpv::expand_path;
-        use std::path::Path;
-        use tokio::io::AsyncWriteExt;
+        save_cache("database", self).await?;
+        save_cache("hashes", &self.hashes).await
+    }
+
+    /// the hashes are loaded even when `database` is missing, and the other way round
+    pub async fn load_from_bincode() -> Result<Self> {
+        let mut linkage = load_cache::<Linkage>("database").await.unwrap_or_default();
+        if let Ok(hashes) = load_cache("hashes").await {
+            linkage.hashes = hashes;
+        }
+        Ok(linkage)
+    }
+}
 
-        let encoded: Vec<u8> = bincode::serde::encode_to_vec(self, bincode::config::legacy())?;
-        let path_str = expand_path("~~/files/danmaku/database")?;
-        let path = Path::new(&path_str);
+/// what is known about a local file while it stays unchanged
+#[derive(Debug, Deserialize, Serialize)]
+pub struct LocalFile {
+    size: u64,
+    modified: SystemTime,
+    hash: String,
+    pub epid: Option<usize>,
+}
 
-        if !path.parent().expect("no parent dir").exists() {
-            std::fs::create_dir_all(path.parent().expect("no parent dir"))?;
+/// local files by path, so that reopening a file needs neither hashing nor matching
+#[derive(Debug, Deserialize, Serialize)]
+pub struct LocalFiles {
+    files: LimitedHashMap<String, LocalFile>,
+}
+
+impl Default for LocalFiles {
+    fn default() -> Self {
+        LocalFiles {
+            files: LimitedHashMap::new(1000),
         }
+    }
+}
 
-        let mut file = tokio::fs::OpenOptions::new()
-            .write(true)
-            .truncate(true)
-            .create(true)
-            .open(path)
-            .await?;
+impl LocalFiles {
+    /// the entry of `path` unless the file was modified since it was recorded
+    pub fn get(&self, path: &str) -> Option<&LocalFile> {
+        let (size, modified) = stat(path).ok()?;
+        self.files
+            .get(path)
+            .filter(|file| file.size == size && file.modified == modified)
+    }
 
-        file.write_all(&encoded).await?;
+    /// hash of the file at `path`, only read from disk when not recorded yet
+    pub fn hash(&mut self, path: &str) -> Result<FileHash> {
+        if let Some(file) = self.get(path) {
+            return Ok(FileHash {
+                hash: file.hash.clone(),
+                size: Some(file.size),
+            });
+        }
 
+        let (size, modified) = stat(path)?;
+        let file_hash = get_localfile_hash(path)?;
+        self.files.insert(
+            path.to_string(),
+            LocalFile {
+                size,
+                modified,
+                hash: file_hash.hash.clone(),
+                epid: None,
+            },
+        );
+        Ok(file_hash)
+    }
+
+    /// bind `epid` to the file at `path`, hashing it if needed
+    pub fn bind(&mut self, path: &str, epid: usize) -> Result<()> {
+        self.hash(path)?;
+        if let Some(file) = self.files.get_mut(path) {
+            file.epid = Some(epid);
+        }
         Ok(())
     }
 
-    pub async fn load_from_bincode() -> Result<Self> {
-        use super::mpv::expand_path;
-        use std::path::Path;
-        use tokio::fs::File;
-        use tokio::io::AsyncReadExt;
+    pub async fn save(&self) -> Result<()> {
+        save_cache("files", self).await
+    }
 
-        let path_str = expand_path("~~/files/danmaku/database")?;
-        let path = Path::new(&path_str);
+    pub async fn load() -> Result<Self> {
+        load_cache("files").await
+    }
+}
 
-        let mut file = File::open(path).await?;
-        let mut contents = vec![];
+/// write `value` to `~~/files/danmaku/<name>`
+pub(crate) async fn save_cache(name: &str, value: &impl Serialize) -> Result<()> {
+    let encoded = bincode::serde::encode_to_vec(value, bincode::config::legacy())?;
+    let path = expand_path(&format!("~~/files/danmaku/{}", name))?;
+    if let Some(parent) = Path::new(&path).parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    tokio::fs::write(path, encoded).await?;
+    Ok(())
+}
 
-        file.read_to_end(&mut contents).await?;
+pub(crate) async fn load_cache<T: DeserializeOwned>(name: &str) -> Result<T> {
+    let contents = tokio::fs::read(expand_path(&format!("~~/files/danmaku/{}", name))?).await?;
+    Ok(bincode::serde::decode_from_slice(&contents, bincode::config::legacy())?.0)
+}
 
-        let linkage: Linkage =
-            bincode::serde::decode_from_slice(&contents, bincode::config::legacy())?.0;
-        Ok(linkage)
+/// delete `~~/files/danmaku/<name>` if it exists
+pub(crate) async fn remove_cache(name: &str) -> Result<()> {
+    match tokio::fs::remove_file(expand_path(&format!("~~/files/danmaku/{}", name))?).await {
+        Err(error) if error.kind() != std::io::ErrorKind::NotFound => Err(error.into()),
+        _ => Ok(()),
     }
 }
 
+fn stat(path: &str) -> Result<(u64, SystemTime)> {
+    let metadata = std::fs::metadata(path)?;
+    Ok((metadata.len(), metadata.modified()?))
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct LimitedHashMap<K, V>
 where
@@ -333,6 +501,15 @@ impl<K: std::hash::Hash + Eq + Clone, V> LimitedHashMap<K, V> {
         self.map.get(key)
     }
 
+    fn get_mut<Q>(&mut self, key: &Q) -> Option<&mut V>
+    where
+        Q: ?Sized,
+        K: Borrow<Q>,
+        Q: Hash + Eq,
+    {
+        self.map.get_mut(key)
+    }
+
     fn _len(&self) -> usize {
         self.map.len()
     }
@@ -341,3 +518,75 @@ impl<K: std::hash::Hash + Eq + Clone, V> LimitedHashMap<K, V> {
         self.map.is_empty()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env::temp_dir, fs, path::PathBuf, process};
+
+    /// a file in the temporary directory no other test or test run writes to
+    fn temp_file(name: &str) -> PathBuf {
+        temp_dir().join(format!("mpv-danmaku-{}-{}.mp4", process::id(), name))
+    }
+
+    #[test]
+    fn test_small_file_hash() {
+        let path = temp_file("small");
+        fs::write(&path, b"abc").unwrap();
+        let file_hash = get_localfile_hash(path.to_str().unwrap()).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(file_hash.hash, "900150983cd24fb0d6963f7d28e17f72");
+        assert_eq!(file_hash.size, Some(3));
+    }
+
+    #[test]
+    fn test_content_range_size() {
+        assert_eq!(
+            content_range_size("bytes 0-16777215/123456789"),
+            Some(123456789)
+        );
+        assert_eq!(content_range_size("bytes 0-16777215/*"), None);
+    }
+
+    #[test]
+    fn test_local_files() {
+        let path = temp_file("local");
+        let path_str = path.to_str().unwrap();
+        fs::write(&path, b"abc").unwrap();
+
+        let mut files = LocalFiles::default();
+        files.bind(path_str, 42).unwrap();
+        assert_eq!(files.get(path_str).and_then(|file| file.epid), Some(42));
+        assert_eq!(
+            files.hash(path_str).unwrap().hash,
+            "900150983cd24fb0d6963f7d28e17f72"
+        );
+
+        fs::write(&path, b"abcd").unwrap();
+        assert!(files.get(path_str).is_none());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_old_database() {
+        // the layout of `database` before the hashes were added
+        #[derive(Serialize)]
+        struct OldLinkage {
+            items: HashMap<String, LimitedHashMap<String, TimesId>>,
+            seasons: HashMap<String, LimitedHashMap<String, AnimeOffset>>,
+        }
+
+        let mut linkage = Linkage::new();
+        linkage.insert_items("emby.example.com", "12345", 42);
+        let old = OldLinkage {
+            items: linkage.items,
+            seasons: linkage.seasons,
+        };
+        let encoded = bincode::serde::encode_to_vec(&old, bincode::config::legacy()).unwrap();
+        let (linkage, _) =
+            bincode::serde::decode_from_slice::<Linkage, _>(&encoded, bincode::config::legacy())
+                .unwrap();
+        assert_eq!(linkage.get_items("emby.example.com", "12345"), Some(42));
+        assert_eq!(linkage.get_hashes("0123456789abcdef"), None);
+    }
+}